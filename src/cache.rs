@@ -0,0 +1,86 @@
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use url::Url;
+
+use crate::{
+    document::{Link, Metadata},
+    download::write_atomically,
+};
+
+/// What is remembered of a response that had an ETag or Last-Modified header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// What the links resolve against
+    pub base: Url,
+    pub links: Vec<Link>,
+    pub metadata: Option<Metadata>,
+    pub noindex: bool,
+}
+
+/// Validators, links and metadata of earlier responses, kept in a json file across crawls so
+/// unchanged urls can be requested conditionally and don't have to be parsed again
+#[derive(Debug)]
+pub struct HttpCache {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<Url, CacheEntry>>,
+}
+
+impl HttpCache {
+    /// Loads a cache file. The cache starts out empty if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<HttpCache, String> {
+        let entries = match path.exists() {
+            true => {
+                let content = match fs::read_to_string(path) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        return Err(format!("Cannot read cache file: {}: {}", path.display(), e));
+                    }
+                };
+                match serde_json::from_str(&content) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        return Err(format!("Invalid cache file: {}: {}", path.display(), e));
+                    }
+                }
+            }
+            false => BTreeMap::new(),
+        };
+        debug!(
+            "Loaded {} cached responses from: {}",
+            entries.len(),
+            path.display()
+        );
+
+        Ok(HttpCache {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn get(&self, url: &Url) -> Option<CacheEntry> {
+        self.entries.lock().unwrap().get(url).cloned()
+    }
+
+    pub fn insert(&self, url: Url, entry: CacheEntry) {
+        self.entries.lock().unwrap().insert(url, entry);
+    }
+
+    /// Writes the cache file, keeping the cache of an earlier crawl if the write is interrupted
+    pub fn save(&self) -> Result<(), String> {
+        let content = match serde_json::to_string(&*self.entries.lock().unwrap()) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Cannot serialize cache: {}", e));
+            }
+        };
+        write_atomically(&self.path, content.as_bytes())
+    }
+}
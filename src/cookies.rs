@@ -0,0 +1,60 @@
+use log::{debug, warn};
+use reqwest::cookie::Jar;
+use std::time;
+use url::Url;
+
+/// Adds the cookies of a Netscape-format cookie file (as written by curl and browser extensions)
+/// to `jar`. Returns how many were added
+pub fn load_netscape(jar: &Jar, content: &str) -> usize {
+    let mut count = 0;
+
+    for (i, line) in content.lines().enumerate() {
+        // curl marks HttpOnly cookies with a prefix that would otherwise look like a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line).trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] else {
+            warn!("Invalid line in cookie file: {}", i + 1);
+            continue;
+        };
+        let secure = secure.eq_ignore_ascii_case("true");
+
+        let host = domain.trim_start_matches('.');
+        let url = match Url::parse(&format!(
+            "{}://{}{}",
+            if secure { "https" } else { "http" },
+            host,
+            path
+        )) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Invalid domain in cookie file: {}: {}", domain, e);
+                continue;
+            }
+        };
+
+        let mut cookie = format!("{}={}; Path={}", name, value, path);
+        if include_subdomains.eq_ignore_ascii_case("true") {
+            cookie.push_str(&format!("; Domain={}", host));
+        }
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        // 0 means a session cookie
+        if let Ok(expires) = expires.parse::<u64>() {
+            if expires > 0 {
+                let expires = time::UNIX_EPOCH + time::Duration::from_secs(expires);
+                cookie.push_str(&format!("; Expires={}", httpdate::fmt_http_date(expires)));
+            }
+        }
+
+        debug!("Loaded cookie: {} for {}", name, domain);
+        jar.add_cookie_str(&cookie, &url);
+        count += 1;
+    }
+
+    count
+}
@@ -0,0 +1,777 @@
+use encoding_rs::{Encoding, UTF_8};
+use log::{debug, trace};
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+use crate::robots::Directives;
+
+/// A link found in a document
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    pub url: String,
+    /// Whether the link may be crawled. False for `rel="nofollow"` links
+    pub follow: bool,
+    /// Whether this is the page's `<link rel="canonical">`
+    pub canonical: bool,
+    /// Whether the page loads this itself, like images from `src` or `srcset` and urls in css
+    pub asset: bool,
+}
+
+impl Link {
+    pub fn new(url: String) -> Link {
+        Link {
+            url,
+            follow: true,
+            canonical: false,
+            asset: false,
+        }
+    }
+
+    pub fn asset(url: String) -> Link {
+        Link {
+            asset: true,
+            ..Link::new(url)
+        }
+    }
+}
+
+/// Links of an html page and what they resolve against
+pub struct PageLinks {
+    pub links: Vec<Link>,
+    /// The `href` of the first `<base>` tag, which replaces the page's url for resolving links
+    pub base: Option<String>,
+}
+
+/// Parses an html page. Every function that looks at a page takes the result, so a page is only
+/// parsed once
+pub fn parse_html(doc: &str) -> Result<tl::VDom<'_>, String> {
+    debug!("Parsing html...");
+    match tl::parse(doc, tl::ParserOptions::default()) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Cannot parse html: {}", e)),
+    }
+}
+
+/// Links of an html page. With `pages_only`, only `<a>` and `<area>` links and the
+/// `<link rel="canonical">` are returned, so assets like images, scripts and stylesheets are left out
+pub fn get_urls_from_document(dom: &tl::VDom, pages_only: bool) -> PageLinks {
+    let mut found = Vec::new();
+    let mut base = None;
+
+    trace!("Looping over all elements...");
+    for element in dom.nodes().iter() {
+        let tag = match element.as_tag() {
+            Some(x) => x,
+            None => {
+                continue;
+            }
+        };
+
+        // Changes what links resolve against, but isn't a link itself. Only the first one with an
+        // href counts
+        if tag.name() == "base" {
+            if base.is_none() {
+                base = attribute(tag, "href").map(|x| x.trim().to_string());
+            }
+            continue;
+        }
+
+        if pages_only && !matches!(tag.name().as_bytes(), b"a" | b"area" | b"link") {
+            continue;
+        }
+
+        if let Some(srcset) = attribute(tag, "srcset") {
+            for x in parse_srcset(&srcset) {
+                trace!("Found link in srcset: {}", x);
+                found.push(Link::asset(x.to_string()));
+            }
+        }
+
+        if let Some(style) = attribute(tag, "style") {
+            found.extend(get_urls_from_css(&style).into_iter().map(Link::asset));
+        }
+        if tag.name() == "style" {
+            found.extend(
+                get_urls_from_css(&tag.inner_text(dom.parser()))
+                    .into_iter()
+                    .map(Link::asset),
+            );
+        }
+
+        if tag.name() == "meta" {
+            if let Some(x) = get_url_from_meta(tag) {
+                trace!("Found link in meta tag: {}", x);
+                found.push(Link::new(x));
+            }
+            continue;
+        }
+        // Forms and submit buttons declare where they are submitted to in action/formaction
+        let (value, asset) = match attribute(tag, "href") {
+            Some(x) => (x, false),
+            None => match attribute(tag, "src") {
+                Some(x) => (x, true),
+                None => match attribute(tag, "action").or_else(|| attribute(tag, "formaction")) {
+                    Some(x) => (x, false),
+                    None => continue,
+                },
+            },
+        };
+        let has_rel = |name: &str| {
+            attribute(tag, "rel")
+                .is_some_and(|rel| rel.split_whitespace().any(|x| x.eq_ignore_ascii_case(name)))
+        };
+        let canonical = tag.name() == "link" && has_rel("canonical");
+        // Other <link> tags are stylesheets, icons and the like
+        if pages_only && tag.name() == "link" && !canonical {
+            continue;
+        }
+        trace!("Found link: {}", value);
+
+        found.push(Link {
+            url: value,
+            follow: !has_rel("nofollow"),
+            canonical,
+            asset,
+        });
+    }
+
+    PageLinks { links: found, base }
+}
+
+/// Value of an attribute with its character references decoded, since links like
+/// `?a=1&amp;b=2` are escaped in html. `None` if it is missing or has no value
+fn attribute(tag: &tl::HTMLTag, name: &str) -> Option<String> {
+    match tag.attributes().get(name) {
+        Some(Some(x)) => Some(decode_entities(&x.as_utf8_str())),
+        _ => None,
+    }
+}
+
+/// Urls of a `srcset`, a comma-separated list of `url [descriptor]` candidates. Urls may contain
+/// commas themselves, so like in browsers a comma only ends a candidate after a descriptor, or
+/// right after a url without one
+fn parse_srcset(srcset: &str) -> Vec<&str> {
+    let mut urls = vec![];
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|x: char| x.is_ascii_whitespace() || x == ',');
+        if rest.is_empty() {
+            return urls;
+        }
+        let (url, after) = rest.split_at(
+            rest.find(|x: char| x.is_ascii_whitespace())
+                .unwrap_or(rest.len()),
+        );
+        rest = after;
+
+        let trimmed = url.trim_end_matches(',');
+        if !trimmed.is_empty() {
+            urls.push(trimmed);
+        }
+        if trimmed.len() < url.len() {
+            continue;
+        }
+        // Descriptors like `100w` or `2x` end at the next comma that isn't in parentheses
+        let mut depth = 0usize;
+        let end = rest
+            .char_indices()
+            .find(|&(_, x)| {
+                match x {
+                    '(' => depth += 1,
+                    ')' => depth = depth.saturating_sub(1),
+                    ',' if depth == 0 => return true,
+                    _ => {}
+                }
+                false
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        rest = &rest[end..];
+    }
+}
+
+/// Target of a `<meta http-equiv="refresh">` redirect, or the url of an Open Graph tag like
+/// `<meta property="og:image">`
+fn get_url_from_meta(tag: &tl::HTMLTag) -> Option<String> {
+    let attribute = |name: &str| attribute(tag, name).map(|x| x.trim().to_string());
+    let content = attribute("content")?;
+
+    if attribute("http-equiv").is_some_and(|x| x.eq_ignore_ascii_case("refresh")) {
+        // `5; url=/next`, where the delay, the `url=` and quotes around the url are optional
+        let target = content.trim_start_matches(|x: char| x.is_ascii_digit() || x == '.');
+        let target = match target.trim_start().strip_prefix([';', ',']) {
+            Some(x) => x.trim(),
+            None => target.trim(),
+        };
+        let target = match target.get(..4) {
+            Some(x) if x.eq_ignore_ascii_case("url=") => target[4..].trim(),
+            _ => target,
+        };
+        let target = target.trim_matches(['\'', '"']);
+        return (!target.is_empty()).then(|| target.to_string());
+    }
+
+    let property = attribute("property").or_else(|| attribute("name"))?;
+    match property.to_lowercase().as_str() {
+        "og:url"
+        | "og:image"
+        | "og:image:url"
+        | "og:image:secure_url"
+        | "og:video"
+        | "og:video:url"
+        | "og:video:secure_url"
+        | "og:audio"
+        | "og:audio:url"
+        | "og:audio:secure_url"
+            if !content.is_empty() =>
+        {
+            Some(content)
+        }
+        _ => None,
+    }
+}
+
+/// Finds string literals in JavaScript that look like urls or paths, e.g. `"/api/items"` or
+/// `'https://example.com/x.js'`. This is a heuristic and will find some strings that aren't links
+pub fn get_urls_from_js(js: &str) -> Vec<String> {
+    static STRING_LITERAL: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#""([^"\\\s]*)"|'([^'\\\s]*)'|`([^`\\\s$]*)`"#).unwrap());
+    static URL_LIKE: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(
+            r"^(https?://[^/\s]+|\.{0,2}/[\w\-.~%]+)(/[\w\-.~%!$&'()*+,;=:@]*)*(\?[^#\s]*)?$",
+        )
+        .unwrap()
+    });
+
+    STRING_LITERAL
+        .captures_iter(js)
+        .filter_map(|x| x.iter().skip(1).flatten().next())
+        .map(|x| x.as_str())
+        .filter(|x| URL_LIKE.is_match(x))
+        .map(|x| {
+            trace!("Found link in script: {}", x);
+            x.to_string()
+        })
+        .collect()
+}
+
+/// Contents of all inline `<script>` tags
+pub fn get_scripts_from_document(dom: &tl::VDom) -> Vec<String> {
+    dom.nodes()
+        .iter()
+        .filter_map(|x| x.as_tag())
+        .filter(|x| x.name() == "script" && x.attributes().get("src").is_none())
+        .map(|x| x.inner_text(dom.parser()).to_string())
+        .collect()
+}
+
+/// Directives of `<meta name="robots">` tags, and of tags named after a crawler that `user_agent`
+/// contains, e.g. `<meta name="web-crawler" content="nofollow">`
+pub fn get_robots_meta(dom: &tl::VDom, user_agent: &str) -> Directives {
+    let user_agent = user_agent.to_lowercase();
+    let mut directives = Directives::default();
+    for tag in dom.nodes().iter().filter_map(|x| x.as_tag()) {
+        if tag.name() != "meta" {
+            continue;
+        }
+        let attribute = |name: &str| match tag.attributes().get(name) {
+            Some(Some(x)) => Some(x.as_utf8_str().trim().to_lowercase()),
+            _ => None,
+        };
+        let (name, content) = match (attribute("name"), attribute("content")) {
+            (Some(name), Some(content)) => (name, content),
+            _ => continue,
+        };
+        if name == "robots" || (!name.is_empty() && user_agent.contains(&name)) {
+            directives = directives.union(Directives::parse(&content));
+        }
+    }
+
+    directives
+}
+
+/// What a page says about itself
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// Text of the `<title>`, with whitespace collapsed
+    pub title: Option<String>,
+    /// Content of `<meta name="description">`, with whitespace collapsed
+    pub description: Option<String>,
+    /// `lang` attribute of the `<html>` tag
+    pub lang: Option<String>,
+}
+
+pub fn extract_metadata(dom: &tl::VDom) -> Metadata {
+    let text = |x: &str| {
+        let x = decode_entities(x)
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!x.is_empty()).then_some(x)
+    };
+    let mut metadata = Metadata::default();
+    for tag in dom.nodes().iter().filter_map(|x| x.as_tag()) {
+        let attribute = |name: &str| match tag.attributes().get(name) {
+            Some(Some(x)) => Some(x.as_utf8_str().to_string()),
+            _ => None,
+        };
+        match tag.name().as_utf8_str().to_lowercase().as_str() {
+            // The first title is the one browsers show. Later ones are usually inside an <svg>
+            "title" if metadata.title.is_none() => {
+                metadata.title = text(&tag.inner_text(dom.parser()));
+            }
+            "meta"
+                if metadata.description.is_none()
+                    && attribute("name").is_some_and(|x| x.eq_ignore_ascii_case("description")) =>
+            {
+                metadata.description = attribute("content").and_then(|x| text(&x));
+            }
+            "html" if metadata.lang.is_none() => {
+                metadata.lang = attribute("lang").and_then(|x| text(&x));
+            }
+            _ => {}
+        }
+    }
+
+    metadata
+}
+
+/// Decodes numeric character references and the most common named ones like `&amp;`
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('&') {
+        decoded.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let c = entity.and_then(|x| match x {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => match x.strip_prefix('#') {
+                Some(x) => match x.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => x.parse().ok(),
+                }
+                .and_then(char::from_u32),
+                None => None,
+            },
+        });
+        match (c, entity) {
+            (Some(c), Some(entity)) => {
+                decoded.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Replaces the values of `href` and `src` attributes in raw html with what `rewrite` returns
+/// for them. Values it returns `None` for are kept. Works on bytes so the encoding of the page
+/// doesn't change
+pub fn rewrite_links(doc: &[u8], mut rewrite: impl FnMut(&str) -> Option<String>) -> Vec<u8> {
+    static ATTRIBUTE: LazyLock<regex::bytes::Regex> = LazyLock::new(|| {
+        regex::bytes::Regex::new(
+            r#"(?i)(<[a-z][^>]*?\s(?:href|src)\s*=\s*)(?:"([^"]*)"|'([^']*)'|([^\s>"']+))"#,
+        )
+        .unwrap()
+    });
+
+    ATTRIBUTE
+        .replace_all(doc, |captures: &regex::bytes::Captures| {
+            let prefix = &captures[1];
+            let (value, quote) = match (captures.get(2), captures.get(3), captures.get(4)) {
+                (Some(x), _, _) => (x, "\""),
+                (_, Some(x), _) => (x, "'"),
+                (_, _, Some(x)) => (x, "\""),
+                _ => unreachable!(),
+            };
+            let link = decode_entities(&String::from_utf8_lossy(value.as_bytes()));
+            let mut replaced = prefix.to_vec();
+            match rewrite(link.trim()) {
+                Some(x) => {
+                    trace!("Rewriting link: {} -> {}", link, x);
+                    replaced.extend_from_slice(quote.as_bytes());
+                    replaced.extend_from_slice(x.as_bytes());
+                    replaced.extend_from_slice(quote.as_bytes());
+                }
+                None => replaced.extend_from_slice(&captures[0][prefix.len()..]),
+            }
+            replaced
+        })
+        .into_owned()
+}
+
+pub fn get_urls_from_css(css: &str) -> Vec<String> {
+    let mut found = Vec::new();
+
+    let mut rest = css;
+    while let Some(index) = rest.find("url(") {
+        rest = rest[index + "url(".len()..].trim_start();
+
+        let value = match rest.chars().next() {
+            Some(quote @ ('"' | '\'')) => match rest[1..].find(quote) {
+                Some(end) => {
+                    let value = &rest[1..end + 1];
+                    rest = &rest[end + 2..];
+                    value
+                }
+                None => break,
+            },
+            _ => match rest.find(')') {
+                Some(end) => {
+                    let value = rest[..end].trim();
+                    rest = &rest[end + 1..];
+                    value
+                }
+                None => break,
+            },
+        };
+
+        if value.is_empty() || value.starts_with("data:") {
+            continue;
+        }
+        trace!("Found link in css: {}", value);
+        found.push(value.to_string());
+    }
+
+    found
+}
+
+pub fn mime_type(headers: &HeaderMap) -> Result<&str, String> {
+    match headers.get("content-type") {
+        Some(content_type) => match content_type.to_str() {
+            Ok(content_type_string) => match content_type_string.split(';').next() {
+                Some(x) => Ok(x),
+                None => Err("content-type header is empty".to_string()),
+            },
+            Err(_) => Err("Cannot stringify content-type header".to_string()),
+        },
+        None => Err("Response header doesn't have content-type".to_string()),
+    }
+}
+
+/// Charset parameter of the content-type header, e.g. `shift_jis` for `text/html; charset=Shift_JIS`
+pub fn charset(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get("content-type")?.to_str().ok()?;
+    content_type.split(';').skip(1).find_map(|x| {
+        let (key, value) = x.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| value.trim().trim_matches(['"', '\'']).to_lowercase())
+    })
+}
+
+/// Charset declared by a `<meta charset>` or `<meta http-equiv="content-type">` tag near the start
+/// of a document
+fn meta_charset(bytes: &[u8]) -> Option<String> {
+    static META_CHARSET: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"(?i)<meta[^>]+charset\s*=\s*["']?([\w\-:.]+)"#).unwrap());
+
+    // Browsers only look this far, and the declaration has to be in ASCII anyway
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(1024)]);
+    META_CHARSET.captures(&start).map(|x| x[1].to_lowercase())
+}
+
+/// Decodes a document with the charset of its content-type header, falling back to its meta tag
+/// for html. A byte order mark takes precedence, and UTF-8 is used if nothing else is known
+pub fn decode(bytes: &[u8], charset: Option<&str>, is_html: bool) -> String {
+    let meta = match is_html && charset.is_none() {
+        true => meta_charset(bytes),
+        false => None,
+    };
+    let encoding = charset
+        .or(meta.as_deref())
+        .and_then(|x| {
+            let encoding = Encoding::for_label(x.as_bytes());
+            if encoding.is_none() {
+                debug!("Unknown charset: {}", x);
+            }
+            encoding
+        })
+        .unwrap_or(UTF_8);
+
+    let (text, used, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        debug!("Document isn't valid {}", used.name());
+    }
+    text.into_owned()
+}
+
+/// Media type of a response without parameters, e.g. `text/html` for `text/html; charset=utf-8`
+pub fn content_type(headers: &HeaderMap) -> Option<String> {
+    mime_type(headers)
+        .ok()
+        .map(|x| x.trim().to_lowercase())
+        .filter(|x| !x.is_empty())
+}
+
+/// Size of the body from the content-length header. Unlike `Response::content_length`, this also
+/// works for responses to HEAD requests
+pub fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("content-length")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Whether the response is an html page. XHTML pages count too, since their links are found the
+/// same way. `content_type` already ignores case and surrounding whitespace
+pub fn is_html(headers: &HeaderMap) -> Result<bool, String> {
+    match content_type(headers) {
+        Some(x) => Ok(matches!(x.as_str(), "text/html" | "application/xhtml+xml")),
+        None => Err("Response doesn't have a valid content-type".to_string()),
+    }
+}
+
+/// Whether a body without a content-type looks like an html page, going by a doctype or `<html>`
+/// tag near its start
+pub fn sniff_html(bytes: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&bytes[..bytes.len().min(512)]).to_lowercase();
+    start.contains("<!doctype html") || start.contains("<html")
+}
+
+pub fn is_js(headers: &HeaderMap) -> Result<bool, String> {
+    match content_type(headers) {
+        Some(x) => Ok(matches!(
+            x.as_str(),
+            "text/javascript" | "application/javascript" | "application/x-javascript"
+        )),
+        None => Err("Response doesn't have a valid content-type".to_string()),
+    }
+}
+
+pub fn is_css(headers: &HeaderMap) -> Result<bool, String> {
+    match content_type(headers) {
+        Some(x) => Ok(x == "text/css"),
+        None => Err("Response doesn't have a valid content-type".to_string()),
+    }
+}
+
+/// Whether a media type matches one of `types`. `image/*` matches every image
+pub fn matches_content_type(content_type: &str, types: &[String]) -> bool {
+    types.iter().any(|x| {
+        let x = x.trim().to_lowercase();
+        match x.strip_suffix("/*") {
+            Some(prefix) => content_type
+                .split_once('/')
+                .is_some_and(|(top_level, _)| top_level == prefix),
+            None => x == content_type,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls(html: &str) -> Vec<String> {
+        let dom = parse_html(html).unwrap();
+        get_urls_from_document(&dom, false)
+            .links
+            .into_iter()
+            .map(|x| x.url)
+            .collect()
+    }
+
+    #[test]
+    fn attribute_values_are_decoded() {
+        assert_eq!(
+            urls(
+                r#"<a href="/a?x=1&amp;y=2">a</a><img srcset="/b?x=1&amp;y=2 1x">
+                <div style="background: url(&quot;/c&quot;)"></div>
+                <meta http-equiv="refresh" content="0; url=/d?x=1&amp;y=2">"#
+            ),
+            ["/a?x=1&y=2", "/b?x=1&y=2", "/c", "/d?x=1&y=2"]
+        );
+        let dom = parse_html(r#"<base href="/e?x=1&amp;y=2">"#).unwrap();
+        assert_eq!(
+            get_urls_from_document(&dom, false).base.as_deref(),
+            Some("/e?x=1&y=2")
+        );
+    }
+
+    fn headers(content_type: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", content_type.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn css_urls() {
+        assert_eq!(
+            get_urls_from_css(
+                "a { background: url(a.png) } @font-face { src: url('b.woff'), url( \"c.svg\" ) }
+                 b { background: url(data:image/png;base64,AAAA) } c { background: url() }"
+            ),
+            ["a.png", "b.woff", "c.svg"]
+        );
+    }
+
+    #[test]
+    fn css_urls_in_html() {
+        assert_eq!(
+            urls(
+                r#"<div style="background: url(/a.png)"></div>
+                <style>body { background: url('/b.png') }</style>"#
+            ),
+            ["/a.png", "/b.png"]
+        );
+    }
+
+    #[test]
+    fn meta_refresh_urls() {
+        assert_eq!(
+            urls(
+                r#"<meta http-equiv="refresh" content="0;url=/a">
+                <meta http-equiv="Refresh" content="5; URL='/b'">
+                <meta http-equiv="refresh" content="url=/c">
+                <meta http-equiv="refresh" content="1.5, /d">
+                <meta http-equiv="refresh" content="3">"#
+            ),
+            ["/a", "/b", "/c", "/d"]
+        );
+    }
+
+    #[test]
+    fn open_graph_urls() {
+        assert_eq!(
+            urls(
+                r#"<meta property="og:url" content="https://example.com/a">
+                <meta property="og:image" content="/b.png">
+                <meta name="og:video:url" content="/c.mp4">
+                <meta property="og:title" content="Not a url">
+                <meta property="og:image" content="">"#
+            ),
+            ["https://example.com/a", "/b.png", "/c.mp4"]
+        );
+    }
+
+    #[test]
+    fn js_urls() {
+        assert_eq!(
+            get_urls_from_js(
+                r#"fetch("/api/items"); load('https://example.com/a.js?v=1');
+                const x = `../b/c.json`, y = "not a url", z = 'a/b', w = "/has space";"#
+            ),
+            ["/api/items", "https://example.com/a.js?v=1", "../b/c.json"]
+        );
+    }
+
+    #[test]
+    fn inline_scripts() {
+        let dom =
+            parse_html(r#"<script>var a = "/a";</script><script src="/b.js"></script>"#).unwrap();
+        assert_eq!(get_scripts_from_document(&dom), [r#"var a = "/a";"#]);
+    }
+
+    #[test]
+    fn form_action_urls() {
+        assert_eq!(
+            urls(
+                r#"<form action="/submit" method="get">
+                <button formaction="/other">Send</button></form>"#
+            ),
+            ["/submit", "/other"]
+        );
+    }
+
+    #[test]
+    fn charset_of_content_type() {
+        assert_eq!(
+            charset(&headers(r#"text/html; Charset="Shift_JIS""#)).as_deref(),
+            Some("shift_jis")
+        );
+        assert_eq!(charset(&headers("text/html")), None);
+    }
+
+    #[test]
+    fn charset_of_meta_tag() {
+        assert_eq!(
+            meta_charset(b"<html><meta charset=\"Windows-1252\">").as_deref(),
+            Some("windows-1252")
+        );
+        assert_eq!(
+            meta_charset(
+                br#"<meta http-equiv="content-type" content="text/html; charset=iso-8859-1">"#
+            )
+            .as_deref(),
+            Some("iso-8859-1")
+        );
+        assert_eq!(meta_charset(b"<html><title>charset=x</title>"), None);
+    }
+
+    #[test]
+    fn decode_with_charset() {
+        assert_eq!(decode(b"caf\xe9", Some("iso-8859-1"), false), "café");
+        assert_eq!(
+            decode(b"<meta charset=latin1>caf\xe9", None, true),
+            "<meta charset=latin1>café"
+        );
+        // The header is used over the meta tag, and a byte order mark over both
+        assert_eq!(
+            decode(b"<meta charset=latin1>caf\xc3\xa9", Some("utf-8"), true),
+            "<meta charset=latin1>café"
+        );
+        assert_eq!(
+            decode(b"\xef\xbb\xbfcaf\xc3\xa9", Some("iso-8859-1"), true),
+            "café"
+        );
+        assert_eq!(decode(b"caf\xc3\xa9", Some("unknown"), false), "café");
+    }
+
+    #[test]
+    fn is_html_for_xhtml_and_variants() {
+        assert_eq!(is_html(&headers("text/html")), Ok(true));
+        assert_eq!(is_html(&headers("Text/HTML ; charset=utf-8")), Ok(true));
+        assert_eq!(is_html(&headers("application/xhtml+xml")), Ok(true));
+        assert_eq!(is_html(&headers("application/json")), Ok(false));
+        assert!(is_html(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn sniff_html_looks_at_start() {
+        assert!(sniff_html(b"\n  <!DOCTYPE html><title>a</title>"));
+        assert!(sniff_html(b"<HTML lang=en>"));
+        assert!(!sniff_html(b"{\"html\": true}"));
+        assert!(!sniff_html(
+            &[b" ".repeat(600), b"<html>".to_vec()].concat()
+        ));
+    }
+
+    #[test]
+    fn srcset_urls_can_contain_commas() {
+        assert_eq!(
+            parse_srcset("https://cdn.example.com/w_100,h_50/a.jpg 1x, /b_1,2.jpg 2x"),
+            ["https://cdn.example.com/w_100,h_50/a.jpg", "/b_1,2.jpg"]
+        );
+    }
+
+    #[test]
+    fn srcset_candidates_are_separated_by_commas() {
+        assert_eq!(
+            parse_srcset(" a.jpg 100w,b.jpg  200w ,\n c.jpg"),
+            ["a.jpg", "b.jpg", "c.jpg"]
+        );
+        assert_eq!(parse_srcset("a.jpg, b.jpg"), ["a.jpg", "b.jpg"]);
+        assert_eq!(
+            parse_srcset("a.jpg,, b.jpg (1x, 2x), c.jpg"),
+            ["a.jpg", "b.jpg", "c.jpg"]
+        );
+        assert!(parse_srcset(" , ").is_empty());
+    }
+}
@@ -0,0 +1,407 @@
+use log::{debug, trace};
+use percent_encoding::percent_decode_str;
+use reqwest::Response;
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::throttle::Throttle;
+
+/// Replaces characters that aren't allowed in file names on some platforms
+fn sanitize_component(component: &str) -> String {
+    component
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\0' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Turns a url path into a relative file path. Segments are percent-decoded, and `.`/`..`
+/// segments (including encoded ones) are resolved so the result can't leave its base directory
+fn sanitize_path(url_path: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+
+    for segment in url_path.split('/') {
+        let decoded = percent_decode_str(segment).decode_utf8_lossy();
+        // Decoding may have produced new separators, e.g. from %2F
+        for part in decoded.split(['/', '\\']) {
+            match part {
+                "" | "." => {}
+                ".." => {
+                    path.pop();
+                }
+                _ => path.push(sanitize_component(part)),
+            }
+        }
+    }
+
+    path
+}
+
+/// Where a file is saved: `<base>/<host>/<path>`, with `index.html` appended for html pages
+pub fn document_path(base: &Path, url: &Url, is_html: bool) -> Result<PathBuf, String> {
+    let host_dir = base.join(sanitize_component(match url.host_str() {
+        Some(x) => x,
+        None => {
+            return Err("Cannot get host".to_string());
+        }
+    }));
+    trace!("Working directory: {}", host_dir.display());
+
+    let mut path = host_dir.join(sanitize_path(url.path()));
+    if is_html && path.extension() != Some("html".as_ref()) {
+        path.push("index.html");
+    }
+    trace!("Location before: {}", path.display());
+
+    // Should be impossible after sanitizing, but never write outside of the host directory
+    if !path.starts_with(&host_dir) || path == host_dir {
+        return Err(format!("Invalid file path: {}", path.display()));
+    }
+
+    Ok(path)
+}
+
+/// Finds a previously downloaded copy of `url` and whether it was saved as html
+pub fn find_local_copy(base: &Path, url: &Url) -> Option<(PathBuf, bool)> {
+    [true, false].into_iter().find_map(|is_html| {
+        let path = document_path(base, url, is_html).ok()?;
+        path.is_file().then_some((path, is_html))
+    })
+}
+
+/// A file that was written to disk
+pub struct Saved {
+    pub path: PathBuf,
+    pub bytes: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Creates the directories for the file of `url`. Returns `None` if the file already exists
+/// and `overwrite` isn't set
+fn prepare_path(
+    base: &Path,
+    url: &Url,
+    is_html: bool,
+    overwrite: bool,
+) -> Result<Option<PathBuf>, String> {
+    let path = document_path(base, url, is_html)?;
+
+    let directory = match path.parent() {
+        Some(x) => x,
+        None => {
+            return Err(format!("Invalid file path: {}", path.display()));
+        }
+    };
+    trace!("Creating directories: {}", directory.display());
+    if let Err(e) = fs::create_dir_all(directory) {
+        return Err(format!(
+            "Cannot create directory: {}: {}",
+            directory.display(),
+            e
+        ));
+    }
+
+    if path.exists() && !overwrite {
+        debug!("File already exists. Not saving: {}", path.display());
+        return Ok(None);
+    }
+    Ok(Some(path))
+}
+
+/// Saves a file under `<base>/<host>/<path>`. Missing directories are created.
+/// Existing files are only replaced if `overwrite` is set, otherwise `None` is returned
+pub fn save_document(
+    base: &Path,
+    url: &Url,
+    is_html: bool,
+    content: &[u8],
+    overwrite: bool,
+) -> Result<Option<Saved>, String> {
+    trace!("Downloading file...");
+    let path = match prepare_path(base, url, is_html, overwrite)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    trace!("Writing to file: {}", path.display());
+    let mut f = match fs::File::create(&path) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Cannot create file: {}: {}", path.display(), e));
+        }
+    };
+
+    match f.write_all(content) {
+        Ok(_) => {}
+        Err(e) => {
+            return Err(format!("Cannot write to file: {}: {}", path.display(), e));
+        }
+    };
+
+    Ok(Some(Saved {
+        path,
+        bytes: content.len() as u64,
+        sha256: Sha256::digest(content).into(),
+    }))
+}
+
+/// Like [`save_document`], but writes the response body to the file chunk by chunk instead of
+/// buffering it. The download is aborted once it gets larger than `max_size` bytes. The body is
+/// written to a temporary file that only replaces `path` once it is complete, so a failed download
+/// keeps the file that was there before
+pub async fn stream_document(
+    base: &Path,
+    url: &Url,
+    mut response: Response,
+    overwrite: bool,
+    max_size: Option<u64>,
+    throttle: Option<&Throttle>,
+) -> Result<Option<Saved>, String> {
+    trace!("Streaming file...");
+    let path = match prepare_path(base, url, false, overwrite)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let temporary = temporary_path(&path);
+    trace!("Writing to file: {}", path.display());
+    let mut f = match tokio::fs::File::create(&temporary).await {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Cannot create file: {}: {}", path.display(), e));
+        }
+    };
+
+    let mut written: u64 = 0;
+    let mut hasher = Sha256::new();
+    let result = loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(x)) => x,
+            Ok(None) => break f.flush().await.map_err(|e| e.to_string()),
+            Err(e) => break Err(format!("Cannot read response: {}", e)),
+        };
+        if let Some(throttle) = throttle {
+            throttle.take(chunk.len()).await;
+        }
+        written += chunk.len() as u64;
+        if max_size.is_some_and(|x| written > x) {
+            break Err("Exceeded max file size".to_string());
+        }
+        hasher.update(&chunk);
+        if let Err(e) = f.write_all(&chunk).await {
+            break Err(e.to_string());
+        }
+    };
+
+    drop(f);
+    let result = match result {
+        Ok(_) => tokio::fs::rename(&temporary, &path)
+            .await
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        if let Err(e) = tokio::fs::remove_file(&temporary).await {
+            debug!("Cannot remove partial file: {}: {}", temporary.display(), e);
+        }
+        return Err(format!("Cannot write to file: {}: {}", path.display(), e));
+    }
+
+    Ok(Some(Saved {
+        path,
+        bytes: written,
+        sha256: hasher.finalize().into(),
+    }))
+}
+
+/// Status line and headers of a response, like curl writes them with `--dump-header`
+pub fn format_headers(response: &Response) -> Vec<u8> {
+    let mut headers = format!("{:?} {}\r\n", response.version(), response.status()).into_bytes();
+    for (name, value) in response.headers() {
+        headers.extend_from_slice(name.as_str().as_bytes());
+        headers.extend_from_slice(b": ");
+        headers.extend_from_slice(value.as_bytes());
+        headers.extend_from_slice(b"\r\n");
+    }
+    headers
+}
+
+/// Name for a temporary file next to `path` that no other write uses, even of the same file
+fn temporary_path(path: &Path) -> PathBuf {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    PathBuf::from(temporary)
+}
+
+/// Writes `content` to a temporary file and renames it over `path`, so an interrupted write can't
+/// corrupt what was there before
+pub fn write_atomically(path: &Path, content: &[u8]) -> Result<(), String> {
+    let temporary = temporary_path(path);
+    trace!("Writing file: {}", path.display());
+    let result = match fs::write(&temporary, content) {
+        Ok(_) => fs::rename(&temporary, path),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temporary);
+        return Err(format!("Cannot write to file: {}: {}", path.display(), e));
+    }
+    Ok(())
+}
+
+/// Writes `headers` to a `.headers` file next to the downloaded file at `path`
+pub fn save_headers(path: &Path, headers: &[u8]) -> Result<(), String> {
+    let mut headers_path = path.as_os_str().to_owned();
+    headers_path.push(".headers");
+    let headers_path = PathBuf::from(headers_path);
+
+    trace!("Writing headers to file: {}", headers_path.display());
+    match fs::write(&headers_path, headers) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Cannot write to file: {}: {}",
+            headers_path.display(),
+            e
+        )),
+    }
+}
+
+/// Replaces the file at `path` with a relative symlink to `target`
+#[cfg(unix)]
+pub fn replace_with_link(path: &Path, target: &Path) -> Result<(), String> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let link = relative_path(directory, target);
+
+    if let Err(e) = fs::remove_file(path) {
+        return Err(format!("Cannot remove file: {}: {}", path.display(), e));
+    }
+    match std::os::unix::fs::symlink(&link, path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Cannot create symlink: {}: {}", path.display(), e)),
+    }
+}
+
+/// Symlinks need special privileges on other platforms, so the duplicate is kept
+#[cfg(not(unix))]
+pub fn replace_with_link(_path: &Path, _target: &Path) -> Result<(), String> {
+    Ok(())
+}
+
+/// Path of `target` relative to the directory `from`. Both must be relative to the same base
+pub fn relative_path(from: &Path, target: &Path) -> PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let target: Vec<_> = target.components().collect();
+    let common = from.iter().zip(&target).take_while(|(a, b)| a == b).count();
+
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for component in &target[common..] {
+        path.push(component);
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(url: &str, is_html: bool) -> Result<PathBuf, String> {
+        document_path(Path::new("out"), &Url::parse(url).unwrap(), is_html)
+    }
+
+    #[test]
+    fn dot_segments_stay_in_host_directory() {
+        // The url parser already resolves literal dot segments
+        assert_eq!(
+            path("http://x/../../etc/passwd", false).unwrap(),
+            Path::new("out/x/etc/passwd")
+        );
+    }
+
+    #[test]
+    fn encoded_dot_segments_stay_in_host_directory() {
+        assert_eq!(
+            path("http://x/a/%2e%2e/%2E%2E/%2e%2e/etc/passwd", false).unwrap(),
+            Path::new("out/x/etc/passwd")
+        );
+    }
+
+    #[test]
+    fn encoded_separators_stay_in_host_directory() {
+        assert_eq!(
+            path("http://x/..%2F..%2Fetc%2Fpasswd", false).unwrap(),
+            Path::new("out/x/etc/passwd")
+        );
+        assert_eq!(
+            path("http://x/..%5C..%5Cetc", false).unwrap(),
+            Path::new("out/x/etc")
+        );
+    }
+
+    #[test]
+    fn leading_slash_is_relative() {
+        assert_eq!(sanitize_path("/etc/passwd"), Path::new("etc/passwd"));
+        assert_eq!(
+            path("http://x//etc/passwd", false).unwrap(),
+            Path::new("out/x/etc/passwd")
+        );
+    }
+
+    #[test]
+    fn html_pages_keep_their_file_name() {
+        assert_eq!(
+            path("http://x/page.html", true).unwrap(),
+            Path::new("out/x/page.html")
+        );
+        assert_eq!(
+            path("http://x/docs/", true).unwrap(),
+            Path::new("out/x/docs/index.html")
+        );
+    }
+
+    #[test]
+    fn temporary_paths_are_unique() {
+        let path = Path::new("out/state.json");
+        let (a, b) = (temporary_path(path), temporary_path(path));
+        assert_ne!(a, b);
+        assert_eq!(a.parent(), path.parent());
+    }
+
+    #[test]
+    fn write_atomically_replaces_file() {
+        let directory =
+            std::env::temp_dir().join(format!("web-crawler-atomic-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("state.json");
+        let result = write_atomically(&path, b"a").and_then(|_| write_atomically(&path, b"b"));
+        let content = fs::read(&path);
+        let files = fs::read_dir(&directory).unwrap().count();
+        fs::remove_dir_all(&directory).unwrap();
+
+        result.unwrap();
+        assert_eq!(content.unwrap(), b"b");
+        assert_eq!(files, 1);
+    }
+
+    #[test]
+    fn root_without_file_name_is_rejected() {
+        assert!(path("http://x/", false).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use log::warn;
+use serde::Serialize;
+use std::{fs, path::Path, time::SystemTime};
+use url::Url;
+
+use crate::lines::LineWriter;
+
+/// Something that happened during a crawl
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// A response was received, whatever its status
+    PageFetched { url: &'a Url, status: u16 },
+    /// A url was found for the first time
+    LinkFound {
+        url: &'a Url,
+        found_on: &'a Url,
+        depth: u32,
+    },
+    DownloadSaved {
+        url: &'a Url,
+        path: &'a Path,
+        bytes: u64,
+    },
+    /// A request failed, got an error status, or its response couldn't be read or saved
+    Error { url: &'a Url, error: &'a str },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Newline-delimited json file that every event is appended to as it happens
+#[derive(Debug)]
+pub struct EventLog {
+    writer: LineWriter,
+}
+
+impl EventLog {
+    pub fn create(path: &Path) -> Result<EventLog, String> {
+        match fs::OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => Ok(EventLog {
+                writer: LineWriter::new(path, file),
+            }),
+            Err(e) => Err(format!(
+                "Cannot open events file: {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    pub fn write(&self, event: Event) {
+        let line = Line {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            event: &event,
+        };
+        match serde_json::to_string(&line) {
+            Ok(x) => self.writer.write(&x),
+            Err(e) => warn!("Cannot serialize event: {}", e),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use log::{debug, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use url::Url;
+
+/// Name of the index file in the output directory
+const FILE_NAME: &str = ".web-crawler-downloaded";
+
+/// Urls of files that were downloaded by earlier crawls, kept in a file with one url per line.
+/// Urls added during a crawl are appended to it right away
+#[derive(Debug)]
+pub struct DownloadIndex {
+    path: PathBuf,
+    urls: Mutex<HashSet<Url>>,
+    file: Mutex<fs::File>,
+}
+
+impl DownloadIndex {
+    /// Loads the index of `output_dir`, creating it if it doesn't exist
+    pub fn open(output_dir: &Path) -> Result<DownloadIndex, String> {
+        if let Err(e) = fs::create_dir_all(output_dir) {
+            return Err(format!(
+                "Cannot create directory: {}: {}",
+                output_dir.display(),
+                e
+            ));
+        }
+        let path = output_dir.join(FILE_NAME);
+
+        let urls: HashSet<Url> = match fs::read_to_string(&path) {
+            Ok(content) => content
+                .lines()
+                .filter_map(|line| Url::parse(line.trim()).ok())
+                .collect(),
+            Err(_) => HashSet::new(),
+        };
+        debug!(
+            "Loaded {} downloaded urls from: {}",
+            urls.len(),
+            path.display()
+        );
+
+        match fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => Ok(DownloadIndex {
+                path,
+                urls: Mutex::new(urls),
+                file: Mutex::new(file),
+            }),
+            Err(e) => Err(format!(
+                "Cannot open download index: {}: {}",
+                path.display(),
+                e
+            )),
+        }
+    }
+
+    pub fn contains(&self, url: &Url) -> bool {
+        self.urls.lock().unwrap().contains(url)
+    }
+
+    pub fn add(&self, url: &Url) {
+        if !self.urls.lock().unwrap().insert(url.clone()) {
+            return;
+        }
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", url) {
+            warn!("Cannot write to file: {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,3176 @@
+//! Finds every page, image, and script on a website (and downloads it)
+
+mod cache;
+mod cookies;
+pub mod document;
+mod download;
+pub mod events;
+mod index;
+mod lines;
+pub mod live;
+pub mod robots;
+pub mod sitemap;
+mod snapshot;
+mod throttle;
+
+use log::{debug, error, info, trace, warn};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use regex::Regex;
+use reqwest::{
+    cookie::Jar,
+    header::{
+        HeaderMap, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, LOCATION,
+        REFERER,
+    },
+    redirect, Client, Identity, Method, StatusCode,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time,
+};
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use url::Url;
+
+use cache::{CacheEntry, HttpCache};
+use document::{
+    charset, content_length, content_type, decode, extract_metadata, get_robots_meta,
+    get_scripts_from_document, get_urls_from_css, get_urls_from_document, get_urls_from_js, is_css,
+    is_html, is_js, matches_content_type, parse_html, rewrite_links, sniff_html, Link, Metadata,
+};
+use download::{
+    find_local_copy, format_headers, relative_path, replace_with_link, save_document, save_headers,
+    stream_document, Saved,
+};
+use events::{Event, EventLog};
+use index::DownloadIndex;
+use live::{LiveExport, Scope};
+use robots::{Directives, RobotsCache};
+use snapshot::Snapshot;
+use throttle::Throttle;
+
+/// How often the state file is written during a crawl
+const STATE_FILE_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
+/// How many times in a row a group of path segments has to appear for the url to be taken for a
+/// crawler trap, like `/a/a/a` or `/a/b/a/b/a/b`
+const TRAP_REPEATS: usize = 3;
+
+/// Error of requests that took longer than `request_timeout`
+const TIMED_OUT: &str = "Request timed out";
+
+/// User-Agent sent when none is configured
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Options controlling a crawl
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Url the crawl starts from
+    pub seed: Url,
+    /// More urls the crawl starts from. Their hosts are internal too
+    pub seeds: Vec<Url>,
+    /// Save every fetched file under `output_dir`
+    pub download: bool,
+    /// Where downloaded files are saved
+    pub output_dir: PathBuf,
+    /// Replace files that were already downloaded
+    pub overwrite: bool,
+    /// Write the status line and headers of every download to a `.headers` file next to it
+    pub save_headers: bool,
+    /// Once the crawl is done, point links in downloaded html pages to the local copies of the
+    /// files they link to, so the pages can be browsed offline
+    pub mirror: bool,
+    /// Send If-Modified-Since for files that were already downloaded and reuse them if unchanged
+    pub if_modified: bool,
+    /// Don't request files that an earlier crawl already downloaded to `output_dir`. Pages are
+    /// still fetched so their links are found
+    pub skip_downloaded: bool,
+    /// If not empty, only files with one of these content types are saved
+    pub download_types: Vec<String>,
+    /// Files larger than this aren't downloaded, in bytes
+    pub max_file_size: Option<u64>,
+    /// Response bodies of all workers together are read at most this many bytes per second
+    pub max_bandwidth: Option<u64>,
+    /// Send a HEAD request first and only download bodies that are parsed or saved
+    pub head_first: bool,
+    /// Crawl other websites that are linked to
+    pub crawl_external: bool,
+    /// Treat subdomains of the seed's registrable domain as internal
+    pub include_subdomains: bool,
+    /// If not empty, only these domains (and their subdomains) are internal instead of the sites
+    /// of the seeds
+    pub internal_domains: Vec<String>,
+    /// Other domains (and their subdomains) that are crawled too
+    pub allow_domains: Vec<String>,
+    /// Domains (and their subdomains) that are never crawled, even with `crawl_external`
+    pub deny_domains: Vec<String>,
+    /// Urls longer than this are recorded but not fetched
+    pub max_url_length: usize,
+    /// Urls with more path segments than this are recorded but not fetched
+    pub max_path_segments: Option<usize>,
+    /// Paths starting with any of these are ignored
+    pub exclude: Vec<String>,
+    /// Urls matching any of these aren't crawled. Takes precedence over `include_regex`
+    pub exclude_regex: Vec<Regex>,
+    /// If not empty, only urls matching one of these are crawled
+    pub include_regex: Vec<Regex>,
+    /// If not empty, only urls with one of these file extensions, or none, are fetched
+    pub allow_ext: Vec<String>,
+    /// Urls with one of these file extensions aren't fetched. Takes precedence over `allow_ext`
+    pub deny_ext: Vec<String>,
+    /// Minimum time between requests to the same host
+    pub timeout: time::Duration,
+    /// Up to this much random time is added to `timeout` so requests aren't perfectly regular
+    pub delay_jitter: time::Duration,
+    /// Don't fetch or obey robots.txt, and ignore X-Robots-Tag headers
+    pub ignore_robots: bool,
+    /// Maximum link depth to follow from the seed
+    pub max_depth: Option<u32>,
+    /// Stop discovering new urls once this many have been found
+    pub max_pages: Option<usize>,
+    /// Stop following links into a host once this many of its urls were queued
+    pub per_host_max_pages: Option<usize>,
+    /// Stop following links into new hosts once urls of this many hosts were queued
+    pub max_hosts: Option<usize>,
+    /// Stop the crawl once it has run this long, keeping what was found so far
+    pub max_time: Option<time::Duration>,
+    /// User-Agent header sent with every request
+    pub user_agent: String,
+    /// Don't crawl links marked `rel="nofollow"`
+    pub respect_nofollow: bool,
+    /// Only crawl internal urls under the seed url's path
+    pub same_path: bool,
+    /// Timeout for each request
+    pub request_timeout: time::Duration,
+    /// How many times to retry a request after a network error or 5xx response
+    pub retries: u32,
+    /// Delay before the first retry. Doubles with every attempt
+    pub retry_backoff: time::Duration,
+    /// Fetch external links too, without crawling them
+    pub check_links: bool,
+    /// Number of workers crawling in parallel
+    pub concurrency: usize,
+    /// At most this many requests to the same host are made at a time
+    pub per_host_concurrency: Option<usize>,
+    /// Crawl with a single worker, so the same site always gives the same result. Overrides
+    /// `concurrency`
+    pub deterministic: bool,
+    /// Order in which queued urls are crawled
+    pub strategy: Strategy,
+    /// Seed the crawl with the urls from the seed host's sitemap.xml
+    pub use_sitemap: bool,
+    /// Only fetch the seed, without downloading it, and record whether each of its links would
+    /// be crawled in [`CrawlResult::decisions`]
+    pub dry_run: bool,
+    /// HTTP or SOCKS5 proxy every request is sent through. The `HTTP_PROXY`/`HTTPS_PROXY`
+    /// environment variables are used if unset
+    pub proxy: Option<String>,
+    /// Accept invalid TLS certificates, e.g. self-signed ones. Anyone in between can read and
+    /// change the traffic
+    pub insecure: bool,
+    /// PEM file with the client certificate sent for mutual TLS. Needs `client_key`
+    pub client_cert: Option<PathBuf>,
+    /// PEM file with the PKCS #8 private key of `client_cert`
+    pub client_key: Option<PathBuf>,
+    /// Local address requests are sent from. An unspecified address like `0.0.0.0` only limits
+    /// connections to its IP version
+    pub local_address: Option<IpAddr>,
+    /// Hosts that are connected to at these addresses instead of looking them up in DNS
+    pub resolve: Vec<(String, IpAddr)>,
+    /// Extra headers sent with every request
+    pub headers: HeaderMap,
+    /// Send the page a url was found on as the Referer header, if it is on the same origin
+    pub send_referer: bool,
+    /// With `send_referer`, send the origin of the page to other origins too, except from https
+    /// to http
+    pub cross_origin_referer: bool,
+    /// Cookies as `name=value` sent to the seed host
+    pub cookies: Vec<String>,
+    /// Netscape-format cookie file to load cookies from
+    pub cookie_file: Option<PathBuf>,
+    /// Basic auth credentials sent to the seed host
+    pub auth: Option<Credentials>,
+    /// Maximum number of redirects followed for a single request
+    pub max_redirects: usize,
+    /// Don't follow redirects. The redirect target is crawled like a link instead
+    pub no_redirects: bool,
+    /// Treat pages declaring the same `<link rel="canonical">` as one page, reporting only the
+    /// canonical url
+    pub dedupe_canonical: bool,
+    /// Treat urls that only differ in the order of their query parameters as the same url
+    pub sort_query: bool,
+    /// Keep query strings of found urls instead of stripping them, e.g. for `?page=2` pagination
+    pub keep_query: bool,
+    /// Replace downloads that have the same content as an earlier one with a symlink to it
+    pub dedupe_content: bool,
+    /// Where the progress of the crawl is saved periodically. A crawl is resumed from it if it
+    /// exists
+    pub state_file: Option<PathBuf>,
+    /// File that validators, links and metadata of responses are kept in across crawls, so
+    /// unchanged urls are requested conditionally and not parsed again
+    pub http_cache: Option<PathBuf>,
+    /// File that an [`Event`] is appended to as json for everything that happens during the crawl
+    pub events: Option<PathBuf>,
+    /// Files that found urls are written to while crawling, one per line, each with the urls it
+    /// gets
+    pub live_exports: Vec<(PathBuf, Scope)>,
+    /// Find urls in string literals of inline scripts and JavaScript files
+    pub scan_js: bool,
+    /// Only follow `<a>` and `<area>` links of pages, so assets like images and scripts aren't
+    /// found
+    pub html_only: bool,
+    /// Only fetch assets like images and scripts from the site of the page they are on, or from
+    /// `internal_domains`, even if links to other sites are crawled
+    pub first_party_assets: bool,
+    /// Don't follow links of pages with a nofollow robots meta tag, and record pages marked
+    /// noindex in a meta tag or X-Robots-Tag header
+    pub respect_robots_meta: bool,
+    /// Leave pages marked noindex out of the found urls. Needs `respect_robots_meta`
+    pub exclude_noindex: bool,
+}
+
+impl Config {
+    /// `seed` followed by `seeds`
+    fn start_urls(&self) -> impl Iterator<Item = &Url> {
+        std::iter::once(&self.seed).chain(&self.seeds)
+    }
+
+    /// Whether `url` is on one of `internal_domains`, or on the site of one of the seeds if
+    /// there are none
+    fn is_internal(&self, url: &Url) -> bool {
+        if !self.internal_domains.is_empty() {
+            return matches_domain(url, &self.internal_domains);
+        }
+        self.start_urls()
+            .any(|seed| same_site(url, seed, self.include_subdomains))
+    }
+
+    /// Default options for crawling from `seed`
+    pub fn new(seed: Url) -> Config {
+        Config {
+            seed,
+            seeds: vec![],
+            download: false,
+            output_dir: PathBuf::from("."),
+            overwrite: false,
+            save_headers: false,
+            mirror: false,
+            if_modified: false,
+            skip_downloaded: false,
+            download_types: vec![],
+            max_file_size: None,
+            max_bandwidth: None,
+            head_first: false,
+            crawl_external: false,
+            include_subdomains: false,
+            internal_domains: vec![],
+            allow_domains: vec![],
+            deny_domains: vec![],
+            max_url_length: 300,
+            max_path_segments: None,
+            exclude: vec![],
+            exclude_regex: vec![],
+            include_regex: vec![],
+            allow_ext: vec![],
+            deny_ext: vec![],
+            timeout: time::Duration::from_millis(100),
+            delay_jitter: time::Duration::ZERO,
+            ignore_robots: false,
+            max_depth: None,
+            max_pages: None,
+            per_host_max_pages: None,
+            max_hosts: None,
+            max_time: None,
+            user_agent: USER_AGENT.to_string(),
+            respect_nofollow: false,
+            same_path: false,
+            request_timeout: time::Duration::from_secs(30),
+            retries: 2,
+            retry_backoff: time::Duration::from_millis(500),
+            check_links: false,
+            concurrency: 8,
+            per_host_concurrency: None,
+            deterministic: false,
+            strategy: Strategy::Bfs,
+            use_sitemap: false,
+            dry_run: false,
+            proxy: None,
+            insecure: false,
+            client_cert: None,
+            client_key: None,
+            local_address: None,
+            resolve: vec![],
+            headers: HeaderMap::new(),
+            cookies: vec![],
+            cookie_file: None,
+            auth: None,
+            max_redirects: 10,
+            no_redirects: false,
+            dedupe_canonical: false,
+            sort_query: false,
+            keep_query: false,
+            dedupe_content: false,
+            state_file: None,
+            http_cache: None,
+            events: None,
+            live_exports: vec![],
+            scan_js: false,
+            html_only: false,
+            first_party_assets: false,
+            respect_robots_meta: false,
+            exclude_noindex: false,
+            send_referer: false,
+            cross_origin_referer: false,
+        }
+    }
+}
+
+/// Username and password for HTTP basic auth. The password is never printed
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl FromStr for Credentials {
+    type Err = String;
+
+    /// Parses `user:pass`, or just `user` for no password
+    fn from_str(s: &str) -> Result<Credentials, String> {
+        match s.split_once(':') {
+            Some(("", _)) => Err("Username is empty".to_string()),
+            Some((username, password)) => Ok(Credentials {
+                username: username.to_string(),
+                password: Some(password.to_string()),
+            }),
+            None if s.is_empty() => Err("Username is empty".to_string()),
+            None => Ok(Credentials {
+                username: s.to_string(),
+                password: None,
+            }),
+        }
+    }
+}
+
+/// Order in which queued urls are crawled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Breadth-first: every url of a depth is crawled before going deeper
+    Bfs,
+    /// Depth-first: the links of a page are crawled before the rest of the queue
+    Dfs,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s.to_lowercase().as_str() {
+            "bfs" => Ok(Strategy::Bfs),
+            "dfs" => Ok(Strategy::Dfs),
+            _ => Err(format!("Unknown strategy: {}. Expected bfs or dfs", s)),
+        }
+    }
+}
+
+/// Builds a [`Crawler`], starting from the defaults of [`Config::new`]
+#[derive(Debug, Clone)]
+pub struct CrawlerBuilder {
+    config: Config,
+}
+
+impl CrawlerBuilder {
+    /// More urls the crawl starts from. Their hosts are internal too
+    pub fn seeds(mut self, seeds: Vec<Url>) -> CrawlerBuilder {
+        self.config.seeds = seeds;
+        self
+    }
+
+    /// Save every fetched file under `output_dir`
+    pub fn download(mut self, download: bool) -> CrawlerBuilder {
+        self.config.download = download;
+        self
+    }
+
+    /// Where downloaded files are saved
+    pub fn output_dir(mut self, output_dir: impl Into<PathBuf>) -> CrawlerBuilder {
+        self.config.output_dir = output_dir.into();
+        self
+    }
+
+    /// Replace files that were already downloaded
+    pub fn overwrite(mut self, overwrite: bool) -> CrawlerBuilder {
+        self.config.overwrite = overwrite;
+        self
+    }
+
+    /// Write the status line and headers of every download to a `.headers` file next to it
+    pub fn save_headers(mut self, save_headers: bool) -> CrawlerBuilder {
+        self.config.save_headers = save_headers;
+        self
+    }
+
+    /// Once the crawl is done, point links in downloaded html pages to the local copies of the
+    /// files they link to, so the pages can be browsed offline
+    pub fn mirror(mut self, mirror: bool) -> CrawlerBuilder {
+        self.config.mirror = mirror;
+        self
+    }
+
+    /// Send If-Modified-Since for files that were already downloaded and reuse them if unchanged
+    pub fn if_modified(mut self, if_modified: bool) -> CrawlerBuilder {
+        self.config.if_modified = if_modified;
+        self
+    }
+
+    /// Don't request files that an earlier crawl already downloaded to `output_dir`. Pages are
+    /// still fetched so their links are found
+    pub fn skip_downloaded(mut self, skip_downloaded: bool) -> CrawlerBuilder {
+        self.config.skip_downloaded = skip_downloaded;
+        self
+    }
+
+    /// If not empty, only files with one of these content types are saved
+    pub fn download_types(mut self, download_types: Vec<String>) -> CrawlerBuilder {
+        self.config.download_types = download_types;
+        self
+    }
+
+    /// Files larger than this aren't downloaded, in bytes
+    pub fn max_file_size(mut self, max_file_size: impl Into<Option<u64>>) -> CrawlerBuilder {
+        self.config.max_file_size = max_file_size.into();
+        self
+    }
+
+    /// Response bodies of all workers together are read at most this many bytes per second
+    pub fn max_bandwidth(mut self, max_bandwidth: impl Into<Option<u64>>) -> CrawlerBuilder {
+        self.config.max_bandwidth = max_bandwidth.into();
+        self
+    }
+
+    /// Send a HEAD request first and only download bodies that are parsed or saved
+    pub fn head_first(mut self, head_first: bool) -> CrawlerBuilder {
+        self.config.head_first = head_first;
+        self
+    }
+
+    /// Crawl other websites that are linked to
+    pub fn crawl_external(mut self, crawl_external: bool) -> CrawlerBuilder {
+        self.config.crawl_external = crawl_external;
+        self
+    }
+
+    /// Treat subdomains of the seed's registrable domain as internal
+    pub fn include_subdomains(mut self, include_subdomains: bool) -> CrawlerBuilder {
+        self.config.include_subdomains = include_subdomains;
+        self
+    }
+
+    /// If not empty, only these domains (and their subdomains) are internal instead of the sites
+    /// of the seeds
+    pub fn internal_domains(mut self, internal_domains: Vec<String>) -> CrawlerBuilder {
+        self.config.internal_domains = internal_domains;
+        self
+    }
+
+    /// Other domains (and their subdomains) that are crawled too
+    pub fn allow_domains(mut self, allow_domains: Vec<String>) -> CrawlerBuilder {
+        self.config.allow_domains = allow_domains;
+        self
+    }
+
+    /// Domains (and their subdomains) that are never crawled, even with `crawl_external`
+    pub fn deny_domains(mut self, deny_domains: Vec<String>) -> CrawlerBuilder {
+        self.config.deny_domains = deny_domains;
+        self
+    }
+
+    /// Urls longer than this are recorded but not fetched
+    pub fn max_url_length(mut self, max_url_length: usize) -> CrawlerBuilder {
+        self.config.max_url_length = max_url_length;
+        self
+    }
+
+    /// Urls with more path segments than this are recorded but not fetched
+    pub fn max_path_segments(
+        mut self,
+        max_path_segments: impl Into<Option<usize>>,
+    ) -> CrawlerBuilder {
+        self.config.max_path_segments = max_path_segments.into();
+        self
+    }
+
+    /// Paths starting with any of these are ignored
+    pub fn exclude(mut self, exclude: Vec<String>) -> CrawlerBuilder {
+        self.config.exclude = exclude;
+        self
+    }
+
+    /// Urls matching any of these aren't crawled. Takes precedence over `include_regex`
+    pub fn exclude_regex(mut self, exclude_regex: Vec<Regex>) -> CrawlerBuilder {
+        self.config.exclude_regex = exclude_regex;
+        self
+    }
+
+    /// If not empty, only urls matching one of these are crawled
+    pub fn include_regex(mut self, include_regex: Vec<Regex>) -> CrawlerBuilder {
+        self.config.include_regex = include_regex;
+        self
+    }
+
+    /// If not empty, only urls with one of these file extensions, or none, are fetched
+    pub fn allow_ext(mut self, allow_ext: Vec<String>) -> CrawlerBuilder {
+        self.config.allow_ext = allow_ext;
+        self
+    }
+
+    /// Urls with one of these file extensions aren't fetched. Takes precedence over `allow_ext`
+    pub fn deny_ext(mut self, deny_ext: Vec<String>) -> CrawlerBuilder {
+        self.config.deny_ext = deny_ext;
+        self
+    }
+
+    /// Minimum time between requests to the same host
+    pub fn timeout(mut self, timeout: time::Duration) -> CrawlerBuilder {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Up to this much random time is added to `timeout` so requests aren't perfectly regular
+    pub fn delay_jitter(mut self, delay_jitter: time::Duration) -> CrawlerBuilder {
+        self.config.delay_jitter = delay_jitter;
+        self
+    }
+
+    /// Don't fetch or obey robots.txt, and ignore X-Robots-Tag headers
+    pub fn ignore_robots(mut self, ignore_robots: bool) -> CrawlerBuilder {
+        self.config.ignore_robots = ignore_robots;
+        self
+    }
+
+    /// Maximum link depth to follow from the seed
+    pub fn max_depth(mut self, max_depth: impl Into<Option<u32>>) -> CrawlerBuilder {
+        self.config.max_depth = max_depth.into();
+        self
+    }
+
+    /// Stop discovering new urls once this many have been found
+    pub fn max_pages(mut self, max_pages: impl Into<Option<usize>>) -> CrawlerBuilder {
+        self.config.max_pages = max_pages.into();
+        self
+    }
+
+    /// Stop following links into a host once this many of its urls were queued
+    pub fn per_host_max_pages(
+        mut self,
+        per_host_max_pages: impl Into<Option<usize>>,
+    ) -> CrawlerBuilder {
+        self.config.per_host_max_pages = per_host_max_pages.into();
+        self
+    }
+
+    /// Stop following links into new hosts once urls of this many hosts were queued
+    pub fn max_hosts(mut self, max_hosts: impl Into<Option<usize>>) -> CrawlerBuilder {
+        self.config.max_hosts = max_hosts.into();
+        self
+    }
+
+    /// Stop the crawl once it has run this long, keeping what was found so far
+    pub fn max_time(mut self, max_time: impl Into<Option<time::Duration>>) -> CrawlerBuilder {
+        self.config.max_time = max_time.into();
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> CrawlerBuilder {
+        self.config.user_agent = user_agent.into();
+        self
+    }
+
+    /// Don't crawl links marked `rel="nofollow"`
+    pub fn respect_nofollow(mut self, respect_nofollow: bool) -> CrawlerBuilder {
+        self.config.respect_nofollow = respect_nofollow;
+        self
+    }
+
+    /// Only crawl internal urls under the seed url's path
+    pub fn same_path(mut self, same_path: bool) -> CrawlerBuilder {
+        self.config.same_path = same_path;
+        self
+    }
+
+    /// Timeout for each request
+    pub fn request_timeout(mut self, request_timeout: time::Duration) -> CrawlerBuilder {
+        self.config.request_timeout = request_timeout;
+        self
+    }
+
+    /// How many times to retry a request after a network error or 5xx response
+    pub fn retries(mut self, retries: u32) -> CrawlerBuilder {
+        self.config.retries = retries;
+        self
+    }
+
+    /// Delay before the first retry. Doubles with every attempt
+    pub fn retry_backoff(mut self, retry_backoff: time::Duration) -> CrawlerBuilder {
+        self.config.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Fetch external links too, without crawling them
+    pub fn check_links(mut self, check_links: bool) -> CrawlerBuilder {
+        self.config.check_links = check_links;
+        self
+    }
+
+    /// Number of workers crawling in parallel
+    pub fn concurrency(mut self, concurrency: usize) -> CrawlerBuilder {
+        self.config.concurrency = concurrency;
+        self
+    }
+
+    /// At most this many requests to the same host are made at a time
+    pub fn per_host_concurrency(
+        mut self,
+        per_host_concurrency: impl Into<Option<usize>>,
+    ) -> CrawlerBuilder {
+        self.config.per_host_concurrency = per_host_concurrency.into();
+        self
+    }
+
+    /// Crawl with a single worker, so the same site always gives the same result. Overrides
+    /// `concurrency`
+    pub fn deterministic(mut self, deterministic: bool) -> CrawlerBuilder {
+        self.config.deterministic = deterministic;
+        self
+    }
+
+    /// Order in which queued urls are crawled
+    pub fn strategy(mut self, strategy: Strategy) -> CrawlerBuilder {
+        self.config.strategy = strategy;
+        self
+    }
+
+    /// Seed the crawl with the urls from the seed host's sitemap.xml
+    pub fn use_sitemap(mut self, use_sitemap: bool) -> CrawlerBuilder {
+        self.config.use_sitemap = use_sitemap;
+        self
+    }
+
+    /// Only fetch the seed, without downloading it, and record whether each of its links would
+    /// be crawled in [`CrawlResult::decisions`]
+    pub fn dry_run(mut self, dry_run: bool) -> CrawlerBuilder {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// HTTP or SOCKS5 proxy every request is sent through. The `HTTP_PROXY`/`HTTPS_PROXY`
+    /// environment variables are used if unset
+    pub fn proxy(mut self, proxy: impl Into<Option<String>>) -> CrawlerBuilder {
+        self.config.proxy = proxy.into();
+        self
+    }
+
+    /// Accept invalid TLS certificates, e.g. self-signed ones. Anyone in between can read and
+    /// change the traffic
+    pub fn insecure(mut self, insecure: bool) -> CrawlerBuilder {
+        self.config.insecure = insecure;
+        self
+    }
+
+    /// PEM file with the client certificate sent for mutual TLS. Needs `client_key`
+    pub fn client_cert(mut self, client_cert: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.client_cert = client_cert.into();
+        self
+    }
+
+    /// PEM file with the PKCS #8 private key of `client_cert`
+    pub fn client_key(mut self, client_key: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.client_key = client_key.into();
+        self
+    }
+
+    /// Local address requests are sent from. An unspecified address like `0.0.0.0` only limits
+    /// connections to its IP version
+    pub fn local_address(mut self, local_address: impl Into<Option<IpAddr>>) -> CrawlerBuilder {
+        self.config.local_address = local_address.into();
+        self
+    }
+
+    /// Hosts that are connected to at these addresses instead of looking them up in DNS
+    pub fn resolve(mut self, resolve: Vec<(String, IpAddr)>) -> CrawlerBuilder {
+        self.config.resolve = resolve;
+        self
+    }
+
+    /// Extra headers sent with every request
+    pub fn headers(mut self, headers: HeaderMap) -> CrawlerBuilder {
+        self.config.headers = headers;
+        self
+    }
+
+    /// Send the page a url was found on as the Referer header, if it is on the same origin
+    pub fn send_referer(mut self, send_referer: bool) -> CrawlerBuilder {
+        self.config.send_referer = send_referer;
+        self
+    }
+
+    /// With `send_referer`, send the origin of the page to other origins too, except from https
+    /// to http
+    pub fn cross_origin_referer(mut self, cross_origin_referer: bool) -> CrawlerBuilder {
+        self.config.cross_origin_referer = cross_origin_referer;
+        self
+    }
+
+    /// Cookies as `name=value` sent to the seed host
+    pub fn cookies(mut self, cookies: Vec<String>) -> CrawlerBuilder {
+        self.config.cookies = cookies;
+        self
+    }
+
+    /// Netscape-format cookie file to load cookies from
+    pub fn cookie_file(mut self, cookie_file: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.cookie_file = cookie_file.into();
+        self
+    }
+
+    /// Basic auth credentials sent to the seed host
+    pub fn auth(mut self, auth: impl Into<Option<Credentials>>) -> CrawlerBuilder {
+        self.config.auth = auth.into();
+        self
+    }
+
+    /// Maximum number of redirects followed for a single request
+    pub fn max_redirects(mut self, max_redirects: usize) -> CrawlerBuilder {
+        self.config.max_redirects = max_redirects;
+        self
+    }
+
+    /// Don't follow redirects. The redirect target is crawled like a link instead
+    pub fn no_redirects(mut self, no_redirects: bool) -> CrawlerBuilder {
+        self.config.no_redirects = no_redirects;
+        self
+    }
+
+    /// Treat pages declaring the same `<link rel="canonical">` as one page, reporting only the
+    /// canonical url
+    pub fn dedupe_canonical(mut self, dedupe_canonical: bool) -> CrawlerBuilder {
+        self.config.dedupe_canonical = dedupe_canonical;
+        self
+    }
+
+    /// Treat urls that only differ in the order of their query parameters as the same url
+    pub fn sort_query(mut self, sort_query: bool) -> CrawlerBuilder {
+        self.config.sort_query = sort_query;
+        self
+    }
+
+    /// Keep query strings of found urls instead of stripping them, e.g. for `?page=2` pagination
+    pub fn keep_query(mut self, keep_query: bool) -> CrawlerBuilder {
+        self.config.keep_query = keep_query;
+        self
+    }
+
+    /// Replace downloads that have the same content as an earlier one with a symlink to it
+    pub fn dedupe_content(mut self, dedupe_content: bool) -> CrawlerBuilder {
+        self.config.dedupe_content = dedupe_content;
+        self
+    }
+
+    /// Where the progress of the crawl is saved periodically. A crawl is resumed from it if it
+    /// exists
+    pub fn state_file(mut self, state_file: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.state_file = state_file.into();
+        self
+    }
+
+    /// File that validators, links and metadata of responses are kept in across crawls, so
+    /// unchanged urls are requested conditionally and not parsed again
+    pub fn http_cache(mut self, http_cache: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.http_cache = http_cache.into();
+        self
+    }
+
+    /// File that an [`Event`] is appended to as json for everything that happens during the crawl
+    pub fn events(mut self, events: impl Into<Option<PathBuf>>) -> CrawlerBuilder {
+        self.config.events = events.into();
+        self
+    }
+
+    /// Files that found urls are written to while crawling, one per line, each with the urls it
+    /// gets
+    pub fn live_exports(mut self, live_exports: Vec<(PathBuf, Scope)>) -> CrawlerBuilder {
+        self.config.live_exports = live_exports;
+        self
+    }
+
+    /// Find urls in string literals of inline scripts and JavaScript files
+    pub fn scan_js(mut self, scan_js: bool) -> CrawlerBuilder {
+        self.config.scan_js = scan_js;
+        self
+    }
+
+    /// Only follow `<a>` and `<area>` links of pages, so assets like images and scripts aren't
+    /// found
+    pub fn html_only(mut self, html_only: bool) -> CrawlerBuilder {
+        self.config.html_only = html_only;
+        self
+    }
+
+    /// Only fetch assets like images and scripts from the site of the page they are on, or from
+    /// `internal_domains`, even if links to other sites are crawled
+    pub fn first_party_assets(mut self, first_party_assets: bool) -> CrawlerBuilder {
+        self.config.first_party_assets = first_party_assets;
+        self
+    }
+
+    /// Don't follow links of pages with a nofollow robots meta tag, and record pages marked
+    /// noindex in a meta tag or X-Robots-Tag header
+    pub fn respect_robots_meta(mut self, respect_robots_meta: bool) -> CrawlerBuilder {
+        self.config.respect_robots_meta = respect_robots_meta;
+        self
+    }
+
+    /// Leave pages marked noindex out of the found urls. Needs `respect_robots_meta`
+    pub fn exclude_noindex(mut self, exclude_noindex: bool) -> CrawlerBuilder {
+        self.config.exclude_noindex = exclude_noindex;
+        self
+    }
+
+    pub fn build(self) -> Result<Crawler, String> {
+        Crawler::with_config(self.config)
+    }
+}
+
+/// Everything found during a crawl
+#[derive(Debug, Clone, Default)]
+pub struct CrawlResult {
+    /// Every url found, sorted
+    pub urls: Vec<Url>,
+    /// Found urls on the host of a seed, or its registrable domain with `include_subdomains`.
+    /// Found urls on `internal_domains` instead if any are set
+    pub internal: Vec<Url>,
+    /// Found urls on other hosts
+    pub external: Vec<Url>,
+    /// Links found while crawling as (page, link) pairs
+    pub edges: Vec<(Url, Url)>,
+    /// Link depth from the seed at which every url was first found
+    pub depths: BTreeMap<Url, u32>,
+    /// Page every url was first found on. The seed and urls from the sitemap have none
+    pub referrers: BTreeMap<Url, Url>,
+    /// Status code of every fetched url
+    pub statuses: BTreeMap<Url, u16>,
+    /// Last-Modified header of every fetched url that sent one
+    pub last_modified: BTreeMap<Url, String>,
+    /// Urls that couldn't be fetched with the last error
+    pub failed: Vec<(Url, String)>,
+    /// Urls that weren't fetched because of robots.txt with the pattern of the `Disallow` rule
+    /// that matched
+    pub disallowed: Vec<(Url, String)>,
+    /// Where requested urls were redirected to
+    pub redirects: BTreeMap<Url, Url>,
+    /// Canonical url of every page that declared a different one
+    pub canonicals: BTreeMap<Url, Url>,
+    /// Downloads with the same content as an earlier one as (duplicate, original) pairs
+    pub duplicates: Vec<(Url, Url)>,
+    /// Pages marked noindex, found with `respect_robots_meta`
+    pub noindex: Vec<Url>,
+    /// Metadata of every html page
+    pub metadata: BTreeMap<Url, Metadata>,
+    /// With `dry_run`, every link on the seed page with the reason it wouldn't be crawled, or
+    /// `None` if it would be
+    pub decisions: Vec<(Url, Option<&'static str>)>,
+    pub stats: Stats,
+}
+
+/// Totals of a finished crawl
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    /// Urls that were requested, whether or not that succeeded
+    pub fetched: usize,
+    /// Files saved to disk
+    pub downloads: usize,
+    /// Bytes of response bodies received
+    pub bytes: u64,
+    /// Requests that failed or got an error status, and files that couldn't be read or saved
+    pub errors: usize,
+    /// Wall time of the crawl
+    pub elapsed: time::Duration,
+}
+
+/// State shared between all workers
+struct State {
+    urls: Mutex<FoundUrls>,
+    /// Normalized urls that were fetched, including where they redirected to
+    visited: Mutex<HashSet<Url>>,
+    /// Links found while crawling as (page, link) pairs
+    edges: Mutex<Vec<(Url, Url)>>,
+    /// Link depth from the seed at which every url was first found
+    depths: Mutex<BTreeMap<Url, u32>>,
+    /// Page every url was first found on
+    referrers: Mutex<BTreeMap<Url, Url>>,
+    /// Status code of every fetched url
+    statuses: Mutex<BTreeMap<Url, u16>>,
+    last_modified: Mutex<BTreeMap<Url, String>>,
+    /// Urls that couldn't be fetched after all retries with the last error
+    failed: Mutex<Vec<(Url, String)>>,
+    /// Urls disallowed by robots.txt with the pattern that matched
+    disallowed: Mutex<Vec<(Url, String)>>,
+    /// Where requested urls were redirected to
+    redirects: Mutex<BTreeMap<Url, Url>>,
+    /// Canonical url of every page that declared a different one
+    canonicals: Mutex<BTreeMap<Url, Url>>,
+    /// First url and file saved with each sha256 hash
+    content_hashes: Mutex<HashMap<[u8; 32], (Url, PathBuf)>>,
+    /// Downloads with the same content as an earlier one as (duplicate, original) pairs
+    duplicates: Mutex<Vec<(Url, Url)>>,
+    /// Pages marked noindex
+    noindex: Mutex<HashSet<Url>>,
+    /// Metadata of every html page
+    metadata: Mutex<BTreeMap<Url, Metadata>>,
+    /// Links of the seed page with the reason they aren't crawled, recorded with `dry_run`
+    decisions: Mutex<Vec<(Url, Option<&'static str>)>>,
+    /// Downloaded html pages as (url the content came from, file) pairs, recorded with `mirror`
+    mirrored: Mutex<Vec<(Url, PathBuf)>>,
+    /// How many urls of each host were queued or crawled. Its keys are the hosts seen so far
+    host_pages: Mutex<HashMap<String, usize>>,
+    /// Limits reading response bodies to `max_bandwidth`
+    throttle: Option<Throttle>,
+    /// Time of the latest request to each host
+    latest_requests: Mutex<HashMap<String, time::Instant>>,
+    /// Limits the requests to each host that are made at a time, with `per_host_concurrency`
+    host_slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+    robots: RobotsCache,
+    client: Client,
+    frontier: Mutex<Frontier>,
+    /// Wakes idle workers when urls are queued or the crawl is finished
+    wake: Notify,
+    /// Set to stop the crawl early
+    stop: Arc<AtomicBool>,
+    /// When `max_time` runs out
+    deadline: Option<time::Instant>,
+    progress: Arc<Progress>,
+    events: Option<Arc<EventLog>>,
+    live_exports: Arc<Vec<LiveExport>>,
+    /// Files that were downloaded already, kept with `skip_downloaded`
+    download_index: Option<Arc<DownloadIndex>>,
+    http_cache: Option<Arc<HttpCache>>,
+    filter: Option<Arc<LinkFilter>>,
+}
+
+impl State {
+    fn event(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.write(event);
+        }
+    }
+
+    /// Writes a newly found url to the live exports
+    fn found(&self, url: &Url, config: &Config) {
+        if self.live_exports.is_empty() {
+            return;
+        }
+        let internal = config.is_internal(url);
+        for export in self.live_exports.iter() {
+            export.write(url, internal);
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let (queue, active) = {
+            let frontier = self.frontier.lock().unwrap();
+            (frontier.queue.clone(), frontier.active.clone())
+        };
+        let mut visited = self.visited.lock().unwrap().clone();
+        // Urls that are being crawled have to be crawled again after resuming
+        for (url, _) in &active {
+            visited.remove(url);
+        }
+
+        Snapshot {
+            urls: self.urls.lock().unwrap().urls.clone(),
+            visited,
+            queue: active.into_iter().chain(queue).collect(),
+            edges: self.edges.lock().unwrap().clone(),
+            depths: self.depths.lock().unwrap().clone(),
+            referrers: self.referrers.lock().unwrap().clone(),
+            statuses: self.statuses.lock().unwrap().clone(),
+            last_modified: self.last_modified.lock().unwrap().clone(),
+            failed: self.failed.lock().unwrap().clone(),
+            disallowed: self.disallowed.lock().unwrap().clone(),
+            redirects: self.redirects.lock().unwrap().clone(),
+            noindex: self.noindex.lock().unwrap().clone(),
+            metadata: self.metadata.lock().unwrap().clone(),
+            canonicals: self.canonicals.lock().unwrap().clone(),
+            content_hashes: self
+                .content_hashes
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(hash, (url, path))| (*hash, url.clone(), path.clone()))
+                .collect(),
+            duplicates: self.duplicates.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Every url found so far in the order it was found
+struct FoundUrls {
+    urls: Vec<Url>,
+    /// Normalized `urls`, so checking whether a url was found doesn't have to go through all
+    normalized: HashSet<Url>,
+}
+
+impl FoundUrls {
+    fn new(urls: Vec<Url>, config: &Config) -> FoundUrls {
+        let normalized = urls
+            .iter()
+            .map(|x| normalize_url(x, config.sort_query))
+            .collect();
+        FoundUrls { urls, normalized }
+    }
+
+    fn contains(&self, url: &Url, config: &Config) -> bool {
+        self.normalized
+            .contains(&normalize_url(url, config.sort_query))
+    }
+
+    /// Adds `url` unless an equivalent url was found already. Returns whether it was added
+    fn insert(&mut self, url: &Url, config: &Config) -> bool {
+        if !self
+            .normalized
+            .insert(normalize_url(url, config.sort_query))
+        {
+            return false;
+        }
+        self.urls.push(url.clone());
+        true
+    }
+
+    fn len(&self) -> usize {
+        self.urls.len()
+    }
+}
+
+/// Work queue shared by the workers
+struct Frontier {
+    /// Urls waiting to be crawled with their depth
+    queue: VecDeque<(Url, u32)>,
+    /// Urls that workers are currently crawling with their depth
+    active: Vec<(Url, u32)>,
+}
+
+/// Counters of a running crawl, updated as urls are crawled
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Urls that were requested, whether or not that succeeded
+    pub fetched: AtomicUsize,
+    /// Urls found so far
+    pub found: AtomicUsize,
+    /// Urls waiting to be crawled
+    pub queued: AtomicUsize,
+    /// Files saved to disk
+    pub downloads: AtomicUsize,
+    /// Bytes of response bodies received
+    pub bytes: AtomicU64,
+    /// Requests that failed or got an error status, and files that couldn't be read or saved
+    pub errors: AtomicUsize,
+}
+
+/// Decides whether a link from a page (first argument) to a url (second argument) is followed.
+/// Only asked about links that the configured options would follow
+pub type LinkFilter = dyn Fn(&Url, &Url) -> bool + Send + Sync;
+
+pub struct Crawler {
+    config: Arc<Config>,
+    client: Client,
+    /// Crawl to resume, loaded from the state file
+    resume: Option<Snapshot>,
+    events: Option<Arc<EventLog>>,
+    live_exports: Arc<Vec<LiveExport>>,
+    download_index: Option<Arc<DownloadIndex>>,
+    http_cache: Option<Arc<HttpCache>>,
+    filter: Option<Arc<LinkFilter>>,
+    stop: Arc<AtomicBool>,
+    progress: Arc<Progress>,
+}
+
+impl Crawler {
+    /// Starts building a crawler for `seed`, e.g. `Crawler::new(seed).max_depth(3).build()`
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(seed: Url) -> CrawlerBuilder {
+        CrawlerBuilder {
+            config: Config::new(seed),
+        }
+    }
+
+    pub fn with_config(config: Config) -> Result<Crawler, String> {
+        // Cookies set by the server are kept here too, so they carry over to later requests
+        let jar = Jar::default();
+        for cookie in &config.cookies {
+            for seed in config.start_urls() {
+                jar.add_cookie_str(cookie, seed);
+            }
+        }
+        if let Some(cookie_file) = &config.cookie_file {
+            let content = match fs::read_to_string(cookie_file) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(format!(
+                        "Cannot read cookie file: {}: {}",
+                        cookie_file.display(),
+                        e
+                    ));
+                }
+            };
+            let count = cookies::load_netscape(&jar, &content);
+            debug!("Loaded {} cookies from: {}", count, cookie_file.display());
+        }
+
+        // Shared by every request so connections are kept alive
+        let mut builder = Client::builder()
+            .cookie_provider(Arc::new(jar))
+            .redirect(match config.no_redirects {
+                true => redirect::Policy::none(),
+                false => redirect::Policy::limited(config.max_redirects),
+            })
+            .user_agent(&config.user_agent)
+            // Compressed bodies are decoded before they are parsed or saved
+            .gzip(true)
+            .brotli(true)
+            .deflate(true)
+            .default_headers(config.headers.clone())
+            // Referer is set per request instead, as reqwest would send full urls to other
+            // origins on redirects
+            .referer(false)
+            .timeout(config.request_timeout);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(match reqwest::Proxy::all(proxy) {
+                Ok(x) => x,
+                Err(e) => {
+                    return Err(format!("Invalid proxy: {}: {}", proxy, e));
+                }
+            });
+        }
+        builder = builder.local_address(config.local_address);
+        for (host, address) in &config.resolve {
+            // The port of the url is used instead
+            builder = builder.resolve(host, SocketAddr::new(*address, 0));
+        }
+        if config.insecure {
+            warn!("TLS certificates are not verified. Connections can be intercepted");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                builder = builder.identity(load_identity(cert, key)?);
+            }
+            (None, None) => {}
+            _ => {
+                return Err("A client certificate and key must be given together".to_string());
+            }
+        }
+        let client = match builder.build() {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Cannot build http client: {}", e));
+            }
+        };
+
+        let resume = match &config.state_file {
+            Some(state_file) => Snapshot::load(state_file)?,
+            None => None,
+        };
+        let events = match &config.events {
+            Some(path) => Some(Arc::new(EventLog::create(path)?)),
+            None => None,
+        };
+        let live_exports = config
+            .live_exports
+            .iter()
+            .map(|(path, scope)| LiveExport::create(path, *scope))
+            .collect::<Result<Vec<_>, _>>()?;
+        let http_cache = match &config.http_cache {
+            Some(path) => Some(Arc::new(HttpCache::load(path)?)),
+            None => None,
+        };
+
+        let download_index = match config.download && config.skip_downloaded && !config.dry_run {
+            true => Some(Arc::new(DownloadIndex::open(&config.output_dir)?)),
+            false => None,
+        };
+
+        Ok(Crawler {
+            config: Arc::new(config),
+            client,
+            resume,
+            events,
+            live_exports: Arc::new(live_exports),
+            download_index,
+            http_cache,
+            filter: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            progress: Arc::new(Progress::default()),
+        })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Asks `filter` about every link that would be followed, e.g. to apply business rules or
+    /// look urls up in a database. Links it returns false for are recorded but not crawled
+    pub fn with_filter(mut self, filter: Box<LinkFilter>) -> Crawler {
+        self.filter = Some(Arc::from(filter));
+        self
+    }
+
+    /// Flag that stops a running crawl once set. Urls that are being crawled are finished, and
+    /// [`Crawler::run`] returns what was found up to then
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stop.clone()
+    }
+
+    /// Counters that can be read while [`Crawler::run`] is running
+    pub fn progress(&self) -> Arc<Progress> {
+        self.progress.clone()
+    }
+
+    /// Queue and found urls of a new crawl
+    async fn start_snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        let mut seen = HashSet::new();
+        let mut host_pages: HashMap<String, usize> = HashMap::new();
+        for seed in self.config.start_urls() {
+            if seen.insert(seed.clone()) {
+                snapshot.queue.push((seed.clone(), 0));
+                *host_pages
+                    .entry(seed.host_str().unwrap_or("").to_string())
+                    .or_default() += 1;
+            }
+        }
+
+        if self.config.use_sitemap && !self.config.dry_run {
+            let mut hosts = HashSet::new();
+            for seed in self.config.start_urls() {
+                if !hosts.insert(seed.host_str()) {
+                    continue;
+                }
+                for mut url in sitemap::fetch(&self.client, seed).await {
+                    if !self.config.keep_query {
+                        url.set_query(None);
+                    }
+                    url.set_fragment(None);
+                    if self
+                        .config
+                        .max_pages
+                        .is_some_and(|max_pages| seen.len() >= max_pages)
+                    {
+                        debug!("Max pages reached. Not queueing urls from sitemap");
+                        break;
+                    }
+
+                    // Filtered like the links on the seed
+                    let reason = if seen.contains(&url) {
+                        Some("Already found")
+                    } else if self
+                        .config
+                        .exclude
+                        .iter()
+                        .any(|x| url.path().starts_with(x))
+                    {
+                        Some("Path is excluded")
+                    } else {
+                        skip_reason(seed, &url, true, false, 0, &self.config)
+                            .or_else(|| match &self.filter {
+                                Some(filter) if !filter(seed, &url) => {
+                                    Some("Link filter rejected url")
+                                }
+                                _ => None,
+                            })
+                            .or_else(|| host_limit_reason(&mut host_pages, &url, &self.config))
+                    };
+                    match reason {
+                        Some(reason) => debug!("{}. Not crawling: {}", reason, url),
+                        None => {
+                            seen.insert(url.clone());
+                            snapshot.urls.push(url.clone());
+                            snapshot.depths.insert(url.clone(), 1);
+                            snapshot.queue.push((url, 1));
+                        }
+                    }
+                }
+            }
+            info!("Found {} urls in sitemap", snapshot.urls.len());
+        }
+
+        snapshot
+    }
+
+    pub async fn run(&self) -> CrawlResult {
+        let start = time::Instant::now();
+        let snapshot = match &self.resume {
+            Some(x) => {
+                info!(
+                    "Resuming crawl with {} found and {} queued urls",
+                    x.urls.len(),
+                    x.queue.len()
+                );
+                x.clone()
+            }
+            None => self.start_snapshot().await,
+        };
+
+        let mut host_pages: HashMap<String, usize> = HashMap::new();
+        // Counted once even if a url is both visited and queued after resuming
+        let known: HashSet<&Url> = snapshot
+            .visited
+            .iter()
+            .chain(snapshot.queue.iter().map(|(url, _)| url))
+            .collect();
+        for url in known {
+            *host_pages
+                .entry(url.host_str().unwrap_or("").to_string())
+                .or_default() += 1;
+        }
+
+        let state = Arc::new(State {
+            urls: Mutex::new(FoundUrls::new(snapshot.urls, &self.config)),
+            visited: Mutex::new(snapshot.visited),
+            edges: Mutex::new(snapshot.edges),
+            depths: Mutex::new(snapshot.depths),
+            referrers: Mutex::new(snapshot.referrers),
+            statuses: Mutex::new(snapshot.statuses),
+            last_modified: Mutex::new(snapshot.last_modified),
+            failed: Mutex::new(snapshot.failed),
+            disallowed: Mutex::new(snapshot.disallowed),
+            redirects: Mutex::new(snapshot.redirects),
+            canonicals: Mutex::new(snapshot.canonicals),
+            content_hashes: Mutex::new(
+                snapshot
+                    .content_hashes
+                    .into_iter()
+                    .map(|(hash, url, path)| (hash, (url, path)))
+                    .collect(),
+            ),
+            duplicates: Mutex::new(snapshot.duplicates),
+            noindex: Mutex::new(snapshot.noindex),
+            metadata: Mutex::new(snapshot.metadata),
+            decisions: Mutex::new(vec![]),
+            mirrored: Mutex::new(vec![]),
+            host_pages: Mutex::new(host_pages),
+            throttle: self.config.max_bandwidth.map(Throttle::new),
+            latest_requests: Mutex::new(HashMap::new()),
+            host_slots: Mutex::new(HashMap::new()),
+            robots: RobotsCache::default(),
+            client: self.client.clone(),
+            frontier: Mutex::new(Frontier {
+                queue: snapshot.queue.into(),
+                active: vec![],
+            }),
+            wake: Notify::new(),
+            stop: self.stop.clone(),
+            deadline: self.config.max_time.map(|x| start + x),
+            progress: self.progress.clone(),
+            events: self.events.clone(),
+            live_exports: self.live_exports.clone(),
+            download_index: self.download_index.clone(),
+            http_cache: self.http_cache.clone(),
+            filter: self.filter.clone(),
+        });
+        // Urls found before the crawl started, by an earlier run or in the sitemap
+        for url in &state.urls.lock().unwrap().urls {
+            state.found(url, &self.config);
+        }
+
+        let saver = self.config.state_file.clone().map(|state_file| {
+            let state = state.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(STATE_FILE_INTERVAL).await;
+                    if let Err(e) = state.snapshot().save(&state_file) {
+                        warn!("Cannot save state: {}", e);
+                    }
+                }
+            })
+        });
+
+        // Urls are queued in the order they were found. With one worker they are also crawled in
+        // that order, and nothing depends on which request finishes first
+        let concurrency = match self.config.deterministic {
+            true => 1,
+            false => self.config.concurrency.max(1),
+        };
+        debug!("Crawling with {} workers...", concurrency);
+        let mut workers = JoinSet::new();
+        for _ in 0..concurrency {
+            workers.spawn(worker(state.clone(), self.config.clone()));
+        }
+        while let Some(result) = workers.join_next().await {
+            if let Err(e) = result {
+                error!("Worker failed: {}", e);
+            }
+        }
+        if let (Some(saver), Some(state_file)) = (saver, &self.config.state_file) {
+            saver.abort();
+            match state.snapshot().save(state_file) {
+                Ok(_) => info!("Saved state to file: {}", state_file.display()),
+                Err(e) => error!("Cannot save state: {}", e),
+            }
+        }
+        if let Some(http_cache) = &self.http_cache {
+            if let Err(e) = http_cache.save() {
+                error!("Cannot save cache: {}", e);
+            }
+        }
+        if self.config.mirror {
+            let mirrored = state.mirrored.lock().unwrap().clone();
+            convert_links(&mirrored, &self.config);
+        }
+
+        let canonicals = state.canonicals.lock().unwrap().clone();
+        // Report pages by their canonical url
+        let canonical = |url: Url| match self.config.dedupe_canonical {
+            true => canonicals.get(&url).cloned().unwrap_or(url),
+            false => url,
+        };
+
+        let mut urls: Vec<Url> = state
+            .urls
+            .lock()
+            .unwrap()
+            .urls
+            .iter()
+            .cloned()
+            .map(canonical)
+            .collect();
+        urls.sort();
+        urls.dedup();
+        let mut edges: Vec<(Url, Url)> = state
+            .edges
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .map(|(from, to)| (canonical(from), canonical(to)))
+            // A page's link to its own canonical url
+            .filter(|(from, to)| !self.config.dedupe_canonical || from != to)
+            .collect();
+        edges.sort();
+        edges.dedup();
+        let mut depths: BTreeMap<Url, u32> = BTreeMap::new();
+        for (url, depth) in state.depths.lock().unwrap().iter() {
+            let depth = *depth;
+            depths
+                .entry(canonical(url.clone()))
+                .and_modify(|x| *x = depth.min(*x))
+                .or_insert(depth);
+        }
+        let mut referrers: BTreeMap<Url, Url> = BTreeMap::new();
+        for (url, referrer) in state.referrers.lock().unwrap().iter() {
+            let (url, referrer) = (canonical(url.clone()), canonical(referrer.clone()));
+            if url != referrer {
+                referrers.entry(url).or_insert(referrer);
+            }
+        }
+        let mut failed = state.failed.lock().unwrap().clone();
+        failed.sort();
+        let mut disallowed = state.disallowed.lock().unwrap().clone();
+        disallowed.sort();
+        let statuses = state.statuses.lock().unwrap().clone();
+        let last_modified = state.last_modified.lock().unwrap().clone();
+        let redirects = state.redirects.lock().unwrap().clone();
+        let mut duplicates = state.duplicates.lock().unwrap().clone();
+        duplicates.sort();
+        let decisions = state.decisions.lock().unwrap().clone();
+        let mut noindex: Vec<Url> = state
+            .noindex
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .map(canonical)
+            .collect();
+        noindex.sort();
+        noindex.dedup();
+        let metadata: BTreeMap<Url, Metadata> = state
+            .metadata
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(url, metadata)| (canonical(url.clone()), metadata.clone()))
+            .collect();
+        if self.config.exclude_noindex {
+            urls.retain(|x| noindex.binary_search(x).is_err());
+        }
+        let (internal, external) = urls
+            .iter()
+            .cloned()
+            .partition(|url| self.config.is_internal(url));
+
+        CrawlResult {
+            urls,
+            internal,
+            external,
+            edges,
+            depths,
+            referrers,
+            statuses,
+            last_modified,
+            failed,
+            disallowed,
+            redirects,
+            canonicals,
+            duplicates,
+            noindex,
+            metadata,
+            decisions,
+            stats: Stats {
+                fetched: self.progress.fetched.load(Ordering::Relaxed),
+                downloads: self.progress.downloads.load(Ordering::Relaxed),
+                bytes: self.progress.bytes.load(Ordering::Relaxed),
+                errors: self.progress.errors.load(Ordering::Relaxed),
+                elapsed: start.elapsed(),
+            },
+        }
+    }
+}
+
+/// Normalizes a url so that equivalent urls compare equal: the host is lowercased, default ports
+/// are removed, `.`/`..` segments (including percent-encoded ones) are resolved, a trailing slash
+/// and the fragment are dropped, and, if `sort_query` is set, query parameters are sorted
+pub fn normalize_url(url: &Url, sort_query: bool) -> Url {
+    let mut url = url.clone();
+    url.set_fragment(None);
+
+    // The parser already lowercases hosts and drops default ports for http and https, but not
+    // for other schemes
+    if let Some(host) = url.host_str() {
+        let lowercase = host.to_lowercase();
+        if lowercase != host {
+            let _ = url.set_host(Some(&lowercase));
+        }
+    }
+
+    if !url.cannot_be_a_base() {
+        let mut segments: Vec<&str> = vec![];
+        for segment in url.path().split('/').skip(1) {
+            match segment.to_lowercase().as_str() {
+                "." | "%2e" => {}
+                ".." | ".%2e" | "%2e." | "%2e%2e" => {
+                    segments.pop();
+                }
+                _ => segments.push(segment),
+            }
+        }
+        // `/a/` and `/a` are the same page
+        while segments.last() == Some(&"") {
+            segments.pop();
+        }
+        let path = format!("/{}", segments.join("/"));
+        url.set_path(&path);
+    }
+
+    if url.query() == Some("") {
+        url.set_query(None);
+    } else if sort_query && url.query().is_some() {
+        let mut pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        pairs.sort();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+
+    url
+}
+
+/// Loads a client certificate and its private key from PEM files
+fn load_identity(cert: &Path, key: &Path) -> Result<Identity, String> {
+    let read = |path: &Path| match fs::read(path) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!("Cannot read file: {}: {}", path.display(), e)),
+    };
+    match Identity::from_pkcs8_pem(&read(cert)?, &read(key)?) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!(
+            "Invalid client certificate or key: {}, {}: {}",
+            cert.display(),
+            key.display(),
+            e
+        )),
+    }
+}
+
+/// Whether two urls are on the same host, or, with `include_subdomains`, on the same registrable
+/// domain, e.g. `blog.example.co.uk` and `example.co.uk`
+pub fn same_site(a: &Url, b: &Url, include_subdomains: bool) -> bool {
+    // IP addresses have no subdomains, and psl would take their last two octets for a domain
+    let (a, b) = match (a.domain(), b.domain()) {
+        (Some(a), Some(b)) if include_subdomains => (a, b),
+        _ => return a.host_str().is_some() && a.host_str() == b.host_str(),
+    };
+    // Hosts without a known suffix like `localhost` are their own registrable domain
+    let registrable = |host: &'_ str| psl::domain_str(host).unwrap_or(host).to_string();
+    registrable(a) == registrable(b)
+}
+
+/// Whether the host of `url` is one of `domains` or a subdomain of one
+fn matches_domain(url: &Url, domains: &[String]) -> bool {
+    let host = match url.host_str() {
+        Some(x) => x,
+        None => return false,
+    };
+    domains.iter().any(|domain| {
+        let domain = domain.trim_start_matches('.');
+        host.eq_ignore_ascii_case(domain)
+            || host
+                .to_lowercase()
+                .ends_with(&format!(".{}", domain.to_lowercase()))
+    })
+}
+
+/// Referer header for a request from `from` to `to`, like a browser's
+/// `strict-origin-when-cross-origin` policy without `cross_origin`: the full url (without
+/// credentials and fragment) for the same origin, and, if `cross_origin` is set, only the origin
+/// for other origins. Nothing is sent from https to http
+fn referer(from: &Url, to: &Url, cross_origin: bool) -> Option<HeaderValue> {
+    if from.scheme() == "https" && to.scheme() != "https" {
+        return None;
+    }
+
+    let mut referer = from.clone();
+    let _ = referer.set_username("");
+    let _ = referer.set_password(None);
+    referer.set_fragment(None);
+    if from.origin() != to.origin() {
+        if !cross_origin {
+            return None;
+        }
+        referer.set_path("/");
+        referer.set_query(None);
+    }
+
+    HeaderValue::from_str(referer.as_str()).ok()
+}
+
+/// File extension of the last path segment of `url`, if it has one
+fn extension(url: &Url) -> Option<String> {
+    let name = url.path_segments()?.next_back()?;
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && !extension.is_empty() => {
+            Some(extension.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Why `url` isn't fetched because of its file extension, or `None` if it is
+fn extension_skip_reason(url: &Url, config: &Config) -> Option<&'static str> {
+    let extension = extension(url)?;
+    let matches = |x: &String| x.trim_start_matches('.').eq_ignore_ascii_case(&extension);
+    if config.deny_ext.iter().any(matches) {
+        Some("Extension is denied")
+    } else if !config.allow_ext.is_empty() && !config.allow_ext.iter().any(matches) {
+        Some("Extension isn't allowed")
+    } else {
+        None
+    }
+}
+
+/// Whether `url` is on the host of a seed, but not under the path of any seed on that host
+fn is_outside_seed_paths(url: &Url, config: &Config) -> bool {
+    let mut seeds = config
+        .start_urls()
+        .filter(|seed| seed.host_str() == url.host_str())
+        .peekable();
+    seeds.peek().is_some() && !seeds.any(|seed| is_under_path(url, seed))
+}
+
+/// Whether the path of `url` is the path of `base` or one of its subpaths. `/docs` and `/docs/` are equivalent
+fn is_under_path(url: &Url, base: &Url) -> bool {
+    let base_path = base.path().trim_end_matches('/');
+    match url.path().strip_prefix(base_path) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Requests a url, retrying with exponential backoff on network errors and 5xx responses.
+/// The last 5xx response is returned once retries are exhausted
+async fn fetch(
+    url: &Url,
+    state: &State,
+    config: &Config,
+    headers: HeaderMap,
+    method: Method,
+) -> Result<reqwest::Response, String> {
+    let mut attempt = 0;
+    loop {
+        let mut request = state
+            .client
+            .request(method.clone(), url.as_str())
+            .headers(headers.clone());
+        // Credentials are only meant for the site being crawled
+        if let Some(auth) = &config.auth {
+            if config.start_urls().any(|x| x.host_str() == url.host_str()) {
+                request = request.basic_auth(&auth.username, auth.password.as_ref());
+            }
+        }
+        let error = match request.send().await {
+            Ok(x) if x.status().is_server_error() && attempt < config.retries => {
+                format!("Server error: {}", x.status())
+            }
+            Ok(x) => return Ok(x),
+            Err(e) if e.is_timeout() => TIMED_OUT.to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        if attempt >= config.retries {
+            return Err(error);
+        }
+        let backoff = config
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(attempt));
+        attempt += 1;
+        warn!(
+            "{}: {}. Retrying in {}ms ({}/{})",
+            error,
+            url,
+            backoff.as_millis(),
+            attempt,
+            config.retries
+        );
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Remembers the ETag and Last-Modified header of a response along with its links and what was
+/// recorded about it, to reuse them if the url didn't change next time
+fn cache_response(
+    state: &State,
+    url: &Url,
+    (etag, last_modified): (Option<String>, Option<String>),
+    base: &Url,
+    links: Vec<Link>,
+) {
+    if let Some(http_cache) = &state.http_cache {
+        http_cache.insert(
+            url.clone(),
+            CacheEntry {
+                etag,
+                last_modified,
+                base: base.clone(),
+                links,
+                metadata: state.metadata.lock().unwrap().get(url).cloned(),
+                noindex: state.noindex.lock().unwrap().contains(url),
+            },
+        );
+    }
+}
+
+/// Whether the body of a response with these headers has to be downloaded, to find links in it
+/// or to save it. Bodies without a content type are downloaded to find out what they are
+fn needs_body(headers: &HeaderMap, config: &Config) -> bool {
+    if config
+        .max_file_size
+        .is_some_and(|max_file_size| content_length(headers).is_some_and(|x| x > max_file_size))
+    {
+        return false;
+    }
+    let parsed = is_html(headers).unwrap_or(true)
+        || is_css(headers).unwrap_or(false)
+        || (config.scan_js && is_js(headers).unwrap_or(false));
+    let saved = config.download
+        && !config.dry_run
+        && (config.download_types.is_empty()
+            || content_type(headers)
+                .is_some_and(|x| matches_content_type(&x, &config.download_types)));
+    parsed || saved
+}
+
+/// Replaces a saved file with a link to an earlier file with the same content
+fn dedupe_content(state: &State, url: &Url, saved: Saved) {
+    let mut content_hashes = state.content_hashes.lock().unwrap();
+    match content_hashes.get(&saved.sha256) {
+        Some((original, path)) if *path != saved.path => {
+            match replace_with_link(&saved.path, path) {
+                Ok(_) => debug!("Same content as {}. Linked: {}", original, url),
+                Err(e) => warn!("Cannot link duplicate file: {}: {}", url, e),
+            }
+            state
+                .duplicates
+                .lock()
+                .unwrap()
+                .push((url.clone(), original.clone()));
+        }
+        Some(_) => {}
+        None => {
+            content_hashes.insert(saved.sha256, (url.clone(), saved.path));
+        }
+    }
+}
+
+/// Characters that are escaped in the local paths links are pointed to
+const LOCAL_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'&')
+    .add(b'\'')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?');
+
+/// Points links in the downloaded html `pages` to the local copies of the files they link to.
+/// Links to files that weren't downloaded are kept
+fn convert_links(pages: &[(Url, PathBuf)], config: &Config) {
+    info!("Converting links in {} pages...", pages.len());
+    for (base, path) in pages {
+        // Pages that were replaced with a link to a duplicate are converted through the original
+        if !fs::symlink_metadata(path).is_ok_and(|x| x.is_file()) {
+            continue;
+        }
+        let content = match fs::read(path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Cannot read file: {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        let directory = path.parent().unwrap_or(Path::new(""));
+
+        let converted = rewrite_links(&content, |link| {
+            if link.starts_with('#') {
+                return None;
+            }
+            let mut url = base.join(link).ok()?;
+            let fragment = url.fragment().map(|x| x.to_string());
+            url.set_fragment(None);
+            let (target, _) = find_local_copy(&config.output_dir, &url)?;
+
+            let target = relative_path(directory, &target);
+            let mut local = target
+                .iter()
+                .map(|x| utf8_percent_encode(&x.to_string_lossy(), LOCAL_PATH).to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            if let Some(fragment) = fragment {
+                local.push('#');
+                local.push_str(&fragment);
+            }
+            Some(local)
+        });
+        if converted != content {
+            if let Err(e) = fs::write(path, converted) {
+                warn!("Cannot write to file: {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Reads a response body, failing once it gets larger than `max_size` bytes
+async fn read_body(
+    mut response: reqwest::Response,
+    max_size: Option<u64>,
+    throttle: Option<&Throttle>,
+) -> Result<Vec<u8>, String> {
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if let Some(throttle) = throttle {
+            throttle.take(chunk.len()).await;
+        }
+        body.extend_from_slice(&chunk);
+        if max_size.is_some_and(|x| body.len() as u64 > x) {
+            return Err("Exceeded max file size".to_string());
+        }
+    }
+    Ok(body)
+}
+
+/// Stops the crawl if `max_time` ran out. `url` is queued again then, so it is crawled when
+/// resuming from the state file
+fn deadline_reached(state: &State, url: &Url, depth: u32) -> bool {
+    if state.deadline.is_none_or(|x| time::Instant::now() < x) {
+        return false;
+    }
+    if !state.stop.swap(true, Ordering::Relaxed) {
+        warn!("Max time reached. Stopping crawl");
+    }
+    state
+        .frontier
+        .lock()
+        .unwrap()
+        .queue
+        .push_front((url.clone(), depth));
+    true
+}
+
+/// Fetches a single url and returns the links that should be crawled next
+async fn crawl(url: &Url, state: Arc<State>, config: &Config, depth: u32) -> Vec<Url> {
+    if !should_fetch(url, &state, config, depth).await {
+        return vec![];
+    }
+
+    wait_for_host(url, &state, config).await;
+    // Taken after waiting so the sleep doesn't hold up other requests to the host. Held until the
+    // url is done, so other workers wait for it before requesting the same host
+    let _host_slot = host_slot(url, &state, config).await;
+    // Waiting may have taken until after the deadline
+    if deadline_reached(&state, url, depth) {
+        state
+            .visited
+            .lock()
+            .unwrap()
+            .remove(&normalize_url(url, config.sort_query));
+        return vec![];
+    }
+
+    // Nothing is saved in a dry run
+    let save = config.download && !config.dry_run;
+    // A previous download that the server can tell us is still up to date
+    let local_copy = match save && config.if_modified {
+        true => find_local_copy(&config.output_dir, url),
+        false => None,
+    };
+    // Not usable if the file would have to be saved again
+    let cached = state
+        .http_cache
+        .as_ref()
+        .and_then(|x| x.get(url))
+        .filter(|_| !save || find_local_copy(&config.output_dir, url).is_some());
+    let headers = request_headers(url, &state, config, local_copy.as_ref(), cached.as_ref());
+    let (response, head_only) = match fetch_page(url, &state, config, headers).await {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    // Everything after this is about where the content actually came from
+    let final_url = response.url().clone();
+    if final_url != *url && !record_redirect(url, &final_url, &state, config, depth) {
+        return vec![];
+    }
+    let url = &final_url;
+
+    if !record_status(url, &response, &state) {
+        return vec![];
+    }
+    let validators = validators(&response, &state);
+    let directives = match config.ignore_robots {
+        true => Directives::default(),
+        false => Directives::from_headers(response.headers(), &config.user_agent),
+    };
+    if config.check_links
+        && !config.crawl_external
+        && !config.is_internal(url)
+        && !matches_domain(url, &config.allow_domains)
+    {
+        trace!("Checked external url: {}", url);
+        return vec![];
+    }
+
+    let status = response.status();
+    // What relative links are resolved against. Pages can change it with a <base> tag
+    let (links, base) = match (status, cached) {
+        (StatusCode::NOT_MODIFIED, Some(entry)) => cached_links(url, entry, &state),
+        // Only reached with redirects disabled. The target is followed like any other link
+        _ if status.is_redirection() && status != StatusCode::NOT_MODIFIED => {
+            match redirect_link(url, &response) {
+                Some(x) => (vec![x], url.clone()),
+                None => return vec![],
+            }
+        }
+        _ => {
+            let page = ReadPage {
+                local_copy,
+                head_only,
+                validators: validators.as_ref(),
+            };
+            let body = match read_page(url, response, page, &state, config).await {
+                Some(x) => x,
+                None => return vec![],
+            };
+            let (links, base) = match parse_links(url, &body, directives, &state, config) {
+                Some(x) => x,
+                None => return vec![],
+            };
+            if let Some(validators) = validators {
+                cache_response(&state, url, validators, &base, links.clone());
+            }
+            (links, base)
+        }
+    };
+
+    if config.dedupe_canonical && !follow_canonical(url, &links, &base, &state, config, depth) {
+        return vec![];
+    }
+
+    let next = queue_links(url, &base, links, &state, config, depth);
+
+    // Only the seed is fetched
+    if config.dry_run {
+        return vec![];
+    }
+
+    next
+}
+
+/// Records `url` as found and checks whether it should be fetched. It counts as visited then
+async fn should_fetch(url: &Url, state: &State, config: &Config, depth: u32) -> bool {
+    if state.urls.lock().unwrap().insert(url, config) {
+        state.found(url, config);
+    }
+    state
+        .depths
+        .lock()
+        .unwrap()
+        .entry(url.clone())
+        .or_insert(depth);
+    if url.to_string().len() > config.max_url_length {
+        warn!("URL too long: {}", url);
+        return false;
+    }
+
+    // Checked before the url counts as visited, as it is never fetched
+    if let Some(reason) = extension_skip_reason(url, config) {
+        debug!("{}. Not fetching: {}", reason, url);
+        return false;
+    }
+
+    if deadline_reached(state, url, depth) {
+        return false;
+    }
+
+    if !state
+        .visited
+        .lock()
+        .unwrap()
+        .insert(normalize_url(url, config.sort_query))
+    {
+        debug!("Already visited. Not crawling: {}", url);
+        return false;
+    }
+
+    if state
+        .download_index
+        .as_ref()
+        .is_some_and(|x| x.contains(url))
+    {
+        debug!("Already downloaded. Not fetching: {}", url);
+        return false;
+    }
+
+    if !config.ignore_robots {
+        if let Some(rule) = state
+            .robots
+            .disallowed_by(&state.client, url, &config.user_agent)
+            .await
+        {
+            info!("Disallowed by robots.txt (Disallow: {}): {}", rule, url);
+            state.disallowed.lock().unwrap().push((url.clone(), rule));
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Waits until `timeout`, or the Crawl-delay of robots.txt, has passed since the latest request
+/// to the host of `url`
+async fn wait_for_host(url: &Url, state: &State, config: &Config) {
+    let timeout = match config.ignore_robots {
+        true => None,
+        false => {
+            state
+                .robots
+                .crawl_delay(&state.client, url, &config.user_agent)
+                .await
+        }
+    }
+    .unwrap_or(config.timeout)
+        + match config.delay_jitter.is_zero() {
+            true => time::Duration::ZERO,
+            false => config.delay_jitter.mul_f64(fastrand::f64()),
+        };
+
+    // Reserve the next free slot for this host so the lock isn't held while sleeping
+    let wait = {
+        let mut latest_requests = state.latest_requests.lock().unwrap();
+        let now = time::Instant::now();
+        let host = url.host_str().unwrap_or("").to_string();
+        let slot = match latest_requests.get(&host) {
+            Some(latest_request) => (*latest_request + timeout).max(now),
+            None => now,
+        };
+        latest_requests.insert(host, slot);
+        slot - now
+    };
+    if !wait.is_zero() {
+        debug!("Sleeping for {}ms", wait.as_millis());
+        // No need to wait past the deadline since nothing is requested after it
+        let wait = match state.deadline {
+            Some(x) => wait.min(x.saturating_duration_since(time::Instant::now())),
+            None => wait,
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// One of the `per_host_concurrency` requests that may be made to the host of `url` at a time
+async fn host_slot(url: &Url, state: &State, config: &Config) -> Option<OwnedSemaphorePermit> {
+    let per_host_concurrency = config.per_host_concurrency?;
+    let semaphore = state
+        .host_slots
+        .lock()
+        .unwrap()
+        .entry(url.host_str().unwrap_or("").to_string())
+        .or_insert_with(|| Arc::new(Semaphore::new(per_host_concurrency.max(1))))
+        .clone();
+    semaphore.acquire_owned().await.ok()
+}
+
+/// Headers of the request for `url`. A `local_copy` or `cached` response makes it conditional
+fn request_headers(
+    url: &Url,
+    state: &State,
+    config: &Config,
+    local_copy: Option<&(PathBuf, bool)>,
+    cached: Option<&CacheEntry>,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(Ok(modified)) =
+        local_copy.map(|(path, _)| fs::metadata(path).and_then(|x| x.modified()))
+    {
+        if let Ok(x) = HeaderValue::from_str(&httpdate::fmt_http_date(modified)) {
+            headers.insert(IF_MODIFIED_SINCE, x);
+        }
+    }
+    if let Some(entry) = cached {
+        if let Some(x) = entry
+            .etag
+            .as_deref()
+            .and_then(|x| HeaderValue::from_str(x).ok())
+        {
+            headers.insert(IF_NONE_MATCH, x);
+        }
+        if let Some(x) = entry
+            .last_modified
+            .as_deref()
+            .and_then(|x| HeaderValue::from_str(x).ok())
+        {
+            headers.insert(IF_MODIFIED_SINCE, x);
+        }
+    }
+    if config.send_referer {
+        let referrer = state.referrers.lock().unwrap().get(url).cloned();
+        if let Some(x) = referrer.and_then(|x| referer(&x, url, config.cross_origin_referer)) {
+            headers.insert(REFERER, x);
+        }
+    }
+    headers
+}
+
+/// Requests `url`, and records it as failed if that doesn't work. With `head_first`, the response
+/// to a HEAD request is returned if the body isn't needed, which the returned bool says
+async fn fetch_page(
+    url: &Url,
+    state: &State,
+    config: &Config,
+    headers: HeaderMap,
+) -> Option<(reqwest::Response, bool)> {
+    // The headers tell whether the body is needed at all
+    let head = match config.head_first {
+        true => match fetch(url, state, config, headers.clone(), Method::HEAD).await {
+            Ok(x)
+                if matches!(
+                    x.status(),
+                    StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+                ) =>
+            {
+                debug!("HEAD isn't supported. Using GET: {}", url);
+                None
+            }
+            Ok(x) if x.status().is_success() && needs_body(x.headers(), config) => None,
+            Ok(x) => Some(x),
+            Err(e) => {
+                debug!("HEAD request failed. Using GET: {}: {}", url, e);
+                None
+            }
+        },
+        false => None,
+    };
+    let head_only = head.is_some();
+
+    trace!("Fetching url: {}", url.to_string());
+    let response = match head {
+        Some(x) => Ok(x),
+        None => fetch(url, state, config, headers, Method::GET).await,
+    };
+    state.progress.fetched.fetch_add(1, Ordering::Relaxed);
+    match response {
+        Ok(x) => Some((x, head_only)),
+        Err(e) => {
+            // Slow servers are expected on long crawls, so the crawl just moves on
+            match e == TIMED_OUT {
+                true => warn!("{}. Skipping: {}", e, url),
+                false => error!("Cannot request file: {}: {}", url, e),
+            }
+            state.progress.errors.fetch_add(1, Ordering::Relaxed);
+            state.event(Event::Error { url, error: &e });
+            state.failed.lock().unwrap().push((url.clone(), e));
+            None
+        }
+    }
+}
+
+/// Records `alias` of `url`, which it redirects to or names as canonical, as found on `url` at
+/// the same depth
+fn add_alias(url: &Url, alias: &Url, state: &State, config: &Config, depth: u32) {
+    if state.urls.lock().unwrap().insert(alias, config) {
+        state.found(alias, config);
+        state.depths.lock().unwrap().insert(alias.clone(), depth);
+        state
+            .referrers
+            .lock()
+            .unwrap()
+            .insert(alias.clone(), url.clone());
+    }
+}
+
+/// Records that `url` redirected to `target`. Returns false if `target` was crawled already
+fn record_redirect(url: &Url, target: &Url, state: &State, config: &Config, depth: u32) -> bool {
+    debug!("Redirected: {} -> {}", url, target);
+    state
+        .redirects
+        .lock()
+        .unwrap()
+        .insert(url.clone(), target.clone());
+    add_alias(url, target, state, config, depth);
+    // Many aliases may point at the same page
+    if normalize_url(url, config.sort_query) != normalize_url(target, config.sort_query)
+        && !state
+            .visited
+            .lock()
+            .unwrap()
+            .insert(normalize_url(target, config.sort_query))
+    {
+        debug!("Redirect target was already crawled: {}", target);
+        return false;
+    }
+    true
+}
+
+/// Records the status of the response for `url`. Returns false for error statuses, which count as
+/// errors of the crawl
+fn record_status(url: &Url, response: &reqwest::Response, state: &State) -> bool {
+    let status = response.status();
+    state
+        .statuses
+        .lock()
+        .unwrap()
+        .insert(url.clone(), status.as_u16());
+    if let Some(Ok(last_modified)) = response.headers().get(LAST_MODIFIED).map(|x| x.to_str()) {
+        state
+            .last_modified
+            .lock()
+            .unwrap()
+            .insert(url.clone(), last_modified.to_string());
+    }
+    state.event(Event::PageFetched {
+        url,
+        status: status.as_u16(),
+    });
+
+    if status.is_client_error() || status.is_server_error() {
+        warn!("Got {}: {}", status, url);
+        state.progress.errors.fetch_add(1, Ordering::Relaxed);
+        state.event(Event::Error {
+            url,
+            error: &format!("Got {}", status),
+        });
+        if status.is_server_error() {
+            state
+                .failed
+                .lock()
+                .unwrap()
+                .push((url.clone(), format!("Server error: {}", status)));
+        }
+        return false;
+    }
+    true
+}
+
+/// ETag and Last-Modified header of a successful response, which are kept in the cache once the
+/// response is handled
+fn validators(
+    response: &reqwest::Response,
+    state: &State,
+) -> Option<(Option<String>, Option<String>)> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|x: &HeaderValue| x.to_str().ok())
+            .map(|x| x.to_string())
+    };
+    match (header(ETAG), header(LAST_MODIFIED)) {
+        (None, None) => None,
+        _ if state.http_cache.is_none() || !response.status().is_success() => None,
+        x => Some(x),
+    }
+}
+
+/// Links of a page that wasn't modified since `entry` was cached, and what they resolve against.
+/// What was recorded about the page is restored
+fn cached_links(url: &Url, entry: CacheEntry, state: &State) -> (Vec<Link>, Url) {
+    debug!("Not modified. Using cached links: {}", url);
+    if let Some(x) = entry.metadata {
+        state.metadata.lock().unwrap().insert(url.clone(), x);
+    }
+    if entry.noindex {
+        state.noindex.lock().unwrap().insert(url.clone());
+    }
+    (entry.links, entry.base)
+}
+
+/// Link to the location of a redirect that wasn't followed
+fn redirect_link(url: &Url, response: &reqwest::Response) -> Option<Link> {
+    let status = response.status();
+    match response
+        .headers()
+        .get(LOCATION)
+        .and_then(|x| x.to_str().ok())
+    {
+        Some(location) => {
+            debug!("Got {}: {} -> {}", status, url, location);
+            Some(Link::new(location.to_string()))
+        }
+        None => {
+            warn!("Got {} without a location: {}", status, url);
+            None
+        }
+    }
+}
+
+/// A page whose links are looked for
+struct Body {
+    is_html: bool,
+    is_css: bool,
+    is_js: bool,
+    charset: Option<String>,
+    bytes: Vec<u8>,
+}
+
+/// What is known about a response before its body is read
+struct ReadPage<'a> {
+    /// Downloaded file that is used if the server says it is unchanged
+    local_copy: Option<(PathBuf, bool)>,
+    /// Whether the response is to a HEAD request
+    head_only: bool,
+    /// Cached for bodies that aren't parsed, without links
+    validators: Option<&'a (Option<String>, Option<String>)>,
+}
+
+/// Reads the body of a response, or the local copy the server says is unchanged, and saves it if
+/// it is downloaded. `None` if there is nothing to parse
+async fn read_page(
+    url: &Url,
+    response: reqwest::Response,
+    page: ReadPage<'_>,
+    state: &State,
+    config: &Config,
+) -> Option<Body> {
+    if let (StatusCode::NOT_MODIFIED, Some((path, is_html))) = (response.status(), page.local_copy)
+    {
+        debug!("Not modified. Using downloaded file: {}", path.display());
+        return match fs::read(&path) {
+            Ok(bytes) => Some(Body {
+                is_html,
+                is_css: path.extension().is_some_and(|x| x == "css"),
+                is_js: config.scan_js && path.extension().is_some_and(|x| x == "js"),
+                charset: None,
+                bytes,
+            }),
+            Err(e) => {
+                warn!("Cannot read file: {}: {}", path.display(), e);
+                None
+            }
+        };
+    }
+
+    if let (Some(max_file_size), Some(length)) =
+        (config.max_file_size, content_length(response.headers()))
+    {
+        if length > max_file_size {
+            warn!(
+                "File is larger than max file size ({} bytes). Not downloading: {}",
+                length, url
+            );
+            return None;
+        }
+    }
+    if page.head_only {
+        debug!("Content isn't parsed or saved. Not downloading: {}", url);
+        return None;
+    }
+    // Misconfigured servers leave out the content-type, so the body has to tell
+    let (is_html, sniff) = match is_html(response.headers()) {
+        Ok(x) => (x, false),
+        Err(e) => {
+            debug!("Cannot tell if document is html: {}: {}", url, e);
+            (false, true)
+        }
+    };
+    let is_css = is_css(response.headers()).unwrap_or(false);
+    let is_js = config.scan_js && is_js(response.headers()).unwrap_or(false);
+    let charset = charset(response.headers());
+    // Nothing is saved in a dry run
+    let save = config.download && !config.dry_run;
+    let download = save
+        && (config.download_types.is_empty()
+            || content_type(response.headers())
+                .is_some_and(|x| matches_content_type(&x, &config.download_types)));
+    if save && !download {
+        debug!("Content type isn't in download types. Not saving: {}", url);
+    }
+    let headers = (download && config.save_headers).then(|| format_headers(&response));
+
+    if !is_html && !is_css && !is_js && !sniff {
+        // Nothing to parse, so the body never has to be held in memory
+        if download {
+            stream_download(url, response, state, config, headers.as_deref()).await;
+        } else if let Some(validators) = page.validators {
+            cache_response(state, url, validators.clone(), url, vec![]);
+        }
+        return None;
+    }
+
+    let bytes = match read_body(response, config.max_file_size, state.throttle.as_ref()).await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Cannot parse response as text: {}: {}", url, e);
+            state.progress.errors.fetch_add(1, Ordering::Relaxed);
+            state.event(Event::Error { url, error: &e });
+            return None;
+        }
+    };
+    state
+        .progress
+        .bytes
+        .fetch_add(bytes.len() as u64, Ordering::Relaxed);
+    let is_html = match sniff {
+        true => {
+            let is_html = sniff_html(&bytes);
+            debug!("Document looks like html: {}: {}", is_html, url);
+            is_html
+        }
+        false => is_html,
+    };
+
+    if download && !save_download(url, is_html, &bytes, state, config, headers.as_deref()) {
+        return None;
+    }
+
+    Some(Body {
+        is_html,
+        is_css,
+        is_js,
+        charset,
+        bytes,
+    })
+}
+
+/// Writes the body of a response that isn't parsed straight to a file
+async fn stream_download(
+    url: &Url,
+    response: reqwest::Response,
+    state: &State,
+    config: &Config,
+    headers: Option<&[u8]>,
+) {
+    match stream_document(
+        &config.output_dir,
+        url,
+        response,
+        config.overwrite,
+        config.max_file_size,
+        state.throttle.as_ref(),
+    )
+    .await
+    {
+        Ok(Some(saved)) => {
+            state.progress.downloads.fetch_add(1, Ordering::Relaxed);
+            if let Some(headers) = headers {
+                if let Err(e) = save_headers(&saved.path, headers) {
+                    warn!("Cannot save headers: {}: {}", url, e);
+                }
+            }
+            state
+                .progress
+                .bytes
+                .fetch_add(saved.bytes, Ordering::Relaxed);
+            state.event(Event::DownloadSaved {
+                url,
+                path: &saved.path,
+                bytes: saved.bytes,
+            });
+            if config.dedupe_content {
+                dedupe_content(state, url, saved);
+            }
+            if let Some(index) = &state.download_index {
+                index.add(url);
+            }
+        }
+        // The file exists already
+        Ok(None) => {
+            if let Some(index) = &state.download_index {
+                index.add(url);
+            }
+        }
+        Err(e) => {
+            warn!("Cannot save document: {}: {}", url, e);
+            state.progress.errors.fetch_add(1, Ordering::Relaxed);
+            state.event(Event::Error { url, error: &e });
+        }
+    }
+}
+
+/// Saves a body that was read to be parsed. Returns false if that failed
+fn save_download(
+    url: &Url,
+    is_html: bool,
+    bytes: &[u8],
+    state: &State,
+    config: &Config,
+    headers: Option<&[u8]>,
+) -> bool {
+    match save_document(&config.output_dir, url, is_html, bytes, config.overwrite) {
+        Ok(Some(saved)) => {
+            state.progress.downloads.fetch_add(1, Ordering::Relaxed);
+            if config.mirror && is_html {
+                state
+                    .mirrored
+                    .lock()
+                    .unwrap()
+                    .push((url.clone(), saved.path.clone()));
+            }
+            if let Some(headers) = headers {
+                if let Err(e) = save_headers(&saved.path, headers) {
+                    warn!("Cannot save headers: {}: {}", url, e);
+                }
+            }
+            state.event(Event::DownloadSaved {
+                url,
+                path: &saved.path,
+                bytes: saved.bytes,
+            });
+            if config.dedupe_content {
+                dedupe_content(state, url, saved);
+            }
+            true
+        }
+        Ok(None) => true,
+        Err(e) => {
+            warn!("Cannot save document: {}: {}", url, e);
+            state.progress.errors.fetch_add(1, Ordering::Relaxed);
+            state.event(Event::Error { url, error: &e });
+            false
+        }
+    }
+}
+
+/// Links of a page and what they resolve against, recording its metadata and robots directives
+/// on the way. `None` if its links aren't followed
+fn parse_links(
+    url: &Url,
+    body: &Body,
+    directives: Directives,
+    state: &State,
+    config: &Config,
+) -> Option<(Vec<Link>, Url)> {
+    if !body.is_html && !body.is_css && !body.is_js {
+        return None;
+    }
+    if directives.nofollow {
+        info!(
+            "X-Robots-Tag is nofollow. Not following links from: {}",
+            url
+        );
+        return None;
+    }
+    let text = decode(&body.bytes, body.charset.as_deref(), body.is_html);
+    // Parsed once for everything that is read from the page
+    let dom = match body.is_html {
+        true => match parse_html(&text) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("Cannot get urls from document: {}: {}", url, e);
+                state.progress.errors.fetch_add(1, Ordering::Relaxed);
+                state.event(Event::Error { url, error: &e });
+                return None;
+            }
+        },
+        false => None,
+    };
+
+    if let Some(dom) = &dom {
+        state
+            .metadata
+            .lock()
+            .unwrap()
+            .insert(url.clone(), extract_metadata(dom));
+    }
+
+    if config.respect_robots_meta {
+        let directives = match &dom {
+            Some(dom) => directives.union(get_robots_meta(dom, &config.user_agent)),
+            None => directives,
+        };
+        if directives.noindex {
+            debug!("Page is noindex: {}", url);
+            state.noindex.lock().unwrap().insert(url.clone());
+        }
+        if directives.nofollow {
+            info!(
+                "Robots meta tag is nofollow. Not following links from: {}",
+                url
+            );
+            return None;
+        }
+    }
+
+    let mut base = url.clone();
+    let links = match &dom {
+        Some(dom) => {
+            let page = get_urls_from_document(dom, config.html_only);
+            if let Some(href) = page.base {
+                match url.join(&href) {
+                    Ok(x) => {
+                        debug!("Base url: {} -> {}", url, x);
+                        base = x;
+                    }
+                    Err(e) => warn!("Invalid base url: {}: {}: {}", href, url, e),
+                }
+            }
+            let mut links = page.links;
+            if config.scan_js {
+                links.extend(
+                    get_scripts_from_document(dom)
+                        .iter()
+                        .flat_map(|x| get_urls_from_js(x))
+                        .map(Link::new),
+                );
+            }
+            links
+        }
+        None if body.is_css => get_urls_from_css(&text)
+            .into_iter()
+            .map(Link::asset)
+            .collect(),
+        None => get_urls_from_js(&text).into_iter().map(Link::new).collect(),
+    };
+    Some((links, base))
+}
+
+/// Records the canonical url of a page, if it names another one. Returns false if that was
+/// crawled already, so the links of the page don't have to be followed
+fn follow_canonical(
+    url: &Url,
+    links: &[Link],
+    base: &Url,
+    state: &State,
+    config: &Config,
+    depth: u32,
+) -> bool {
+    let canonical = match links
+        .iter()
+        .find(|x| x.canonical)
+        .and_then(|x| base.join(&x.url).ok())
+        .filter(|x| x != url)
+    {
+        Some(x) => x,
+        None => return true,
+    };
+    debug!("Canonical url: {} -> {}", url, canonical);
+    state
+        .canonicals
+        .lock()
+        .unwrap()
+        .insert(url.clone(), canonical.clone());
+    add_alias(url, &canonical, state, config, depth);
+    if !state
+        .visited
+        .lock()
+        .unwrap()
+        .insert(normalize_url(&canonical, config.sort_query))
+    {
+        debug!(
+            "Canonical page was already crawled. Not following links from: {}",
+            url
+        );
+        return false;
+    }
+    true
+}
+
+/// Links resolved against `base` with whether they are followed and assets. Links that can't be
+/// crawled, like `mailto:` links, are left out
+fn resolve_links(base: &Url, links: Vec<Link>) -> Vec<(Url, bool, bool)> {
+    links
+        .into_iter()
+        .filter_map(|link| match base.join(link.url.trim()) {
+            Ok(x) => Some((x, link.follow, link.asset)),
+            Err(e) => {
+                warn!("Cannot resolve link: {}: {}: {}", link.url, base, e);
+                None
+            }
+        })
+        .filter(|(x, _, _)| {
+            // Skips mailto:, tel:, javascript:, data: and the like
+            let crawlable = matches!(x.scheme(), "http" | "https");
+            if !crawlable {
+                trace!("Ignoring {} url: {}", x.scheme(), x);
+            }
+            crawlable
+        })
+        .collect()
+}
+
+/// Records the links found on `url` and returns the ones that should be crawled next
+fn queue_links(
+    url: &Url,
+    base: &Url,
+    links: Vec<Link>,
+    state: &State,
+    config: &Config,
+    depth: u32,
+) -> Vec<Url> {
+    let mut next = vec![];
+    for (mut i, follow, asset) in resolve_links(base, links) {
+        if !config.keep_query {
+            i.set_query(None);
+        }
+        i.set_fragment(None);
+
+        let excluded = config.exclude.iter().any(|j| i.path().starts_with(j));
+        // Only locked for the check and insert, so other workers can record their links meanwhile
+        let already_found = {
+            let mut urls = state.urls.lock().unwrap();
+            if config
+                .max_pages
+                .is_some_and(|max_pages| urls.len() >= max_pages)
+            {
+                debug!("Max pages reached. Not following links from: {}", url);
+                break;
+            }
+            let already_found = urls.contains(&i, config);
+            if !already_found && !excluded {
+                urls.insert(&i, config);
+            }
+            already_found
+        };
+        state.edges.lock().unwrap().push((url.clone(), i.clone()));
+
+        let reason = if already_found {
+            Some("Already found")
+        } else if excluded {
+            Some("Path is excluded")
+        } else {
+            info!("Found url: {}", i);
+            state.found(&i, config);
+            state.depths.lock().unwrap().insert(i.clone(), depth + 1);
+            state
+                .referrers
+                .lock()
+                .unwrap()
+                .insert(i.clone(), url.clone());
+            state.event(Event::LinkFound {
+                url: &i,
+                found_on: url,
+                depth: depth + 1,
+            });
+            skip_reason(url, &i, follow, asset, depth, config)
+                .or_else(|| match &state.filter {
+                    Some(filter) if !filter(url, &i) => Some("Link filter rejected url"),
+                    _ => None,
+                })
+                .or_else(|| host_limit_reason(&mut state.host_pages.lock().unwrap(), &i, config))
+        };
+        // Every link that isn't crawled says why, so filters are easier to tune
+        match reason {
+            Some(reason) => debug!("{}. Not crawling: {}", reason, i),
+            None => trace!("Url is internal. Crawling: {}", i.to_string()),
+        }
+
+        if config.dry_run {
+            state.decisions.lock().unwrap().push((i.clone(), reason));
+        }
+        if reason.is_none() {
+            next.push(i);
+        }
+    }
+    next
+}
+
+/// Why a link found on `page` isn't crawled, or `None` if it is. Whether it is internal doesn't
+/// depend on `page`, which may be external after a redirect
+fn skip_reason(
+    page: &Url,
+    link: &Url,
+    follow: bool,
+    asset: bool,
+    depth: u32,
+    config: &Config,
+) -> Option<&'static str> {
+    if link.as_str().len() > config.max_url_length {
+        Some("Url is too long")
+    } else if config.max_depth.is_some_and(|max_depth| depth >= max_depth) {
+        Some("Max depth reached")
+    } else if config.respect_nofollow && !follow {
+        Some("Link is nofollow")
+    } else if config
+        .exclude_regex
+        .iter()
+        .any(|x| x.is_match(link.as_str()))
+    {
+        Some("Url matches an exclude regex")
+    } else if !config.include_regex.is_empty()
+        && !config
+            .include_regex
+            .iter()
+            .any(|x| x.is_match(link.as_str()))
+    {
+        Some("Url doesn't match any include regex")
+    } else if config.same_path && is_outside_seed_paths(link, config) {
+        Some("Url is outside of the seed path")
+    } else if matches_domain(link, &config.deny_domains) {
+        Some("Domain is denied")
+    } else if let Some(reason) = extension_skip_reason(link, config) {
+        Some(reason)
+    } else if config.first_party_assets
+        && asset
+        && !(same_site(page, link, config.include_subdomains)
+            || matches_domain(link, &config.internal_domains))
+    {
+        Some("Asset isn't first-party")
+    } else if !(config.is_internal(link)
+        || matches_domain(link, &config.allow_domains)
+        || config.crawl_external
+        || config.check_links)
+    {
+        Some("Url is external")
+    } else if let Some(reason) = trap_reason(link, config) {
+        warn!("Possible crawler trap. {}: {}", reason, link);
+        Some(reason)
+    } else {
+        None
+    }
+}
+
+/// Why `url` isn't crawled because of `max_hosts` or `per_host_max_pages`, or `None` if it is.
+/// It is counted as a page of its host then
+fn host_limit_reason(
+    host_pages: &mut HashMap<String, usize>,
+    url: &Url,
+    config: &Config,
+) -> Option<&'static str> {
+    let host = url.host_str().unwrap_or("");
+    if !host_pages.contains_key(host)
+        && config
+            .max_hosts
+            .is_some_and(|max_hosts| host_pages.len() >= max_hosts)
+    {
+        return Some("Max hosts reached");
+    }
+    let count = host_pages.entry(host.to_string()).or_default();
+    if config.per_host_max_pages.is_some_and(|x| *count >= x) {
+        return Some("Max pages of host reached");
+    }
+    *count += 1;
+    None
+}
+
+/// Why `url` looks like part of an infinite url space, or `None` if it doesn't
+fn trap_reason(url: &Url, config: &Config) -> Option<&'static str> {
+    let segments: Vec<&str> = match url.path_segments() {
+        Some(x) => x.filter(|x| !x.is_empty()).collect(),
+        None => return None,
+    };
+    if config
+        .max_path_segments
+        .is_some_and(|max_path_segments| segments.len() > max_path_segments)
+    {
+        Some("Url has too many path segments")
+    } else if has_repeated_segments(&segments) {
+        Some("Path segments repeat")
+    } else {
+        None
+    }
+}
+
+/// Whether a group of up to three segments appears `TRAP_REPEATS` times in a row
+fn has_repeated_segments(segments: &[&str]) -> bool {
+    (1..=3).any(|len| {
+        segments
+            .windows(len * TRAP_REPEATS)
+            .any(|window| window.chunks(len).all(|chunk| chunk == &window[..len]))
+    })
+}
+
+/// Crawls urls from the frontier until it is empty and no other worker is busy
+async fn worker(state: Arc<State>, config: Arc<Config>) {
+    loop {
+        // Created before checking the queue so a wake-up in between isn't missed
+        let notified = state.wake.notified();
+
+        if state.stop.load(Ordering::Relaxed) {
+            debug!("Crawl was stopped. Not crawling any more urls");
+            state.wake.notify_waiters();
+            return;
+        }
+
+        let next = {
+            let mut frontier = state.frontier.lock().unwrap();
+            let next = match config.strategy {
+                Strategy::Bfs => frontier.queue.pop_front(),
+                Strategy::Dfs => frontier.queue.pop_back(),
+            };
+            match next {
+                Some(x) => {
+                    frontier.active.push(x.clone());
+                    state
+                        .progress
+                        .queued
+                        .store(frontier.queue.len(), Ordering::Relaxed);
+                    Some(x)
+                }
+                None if frontier.active.is_empty() => {
+                    state.wake.notify_waiters();
+                    return;
+                }
+                None => None,
+            }
+        };
+
+        match next {
+            Some((url, depth)) => {
+                // Run on its own task so a panic while crawling one url can't stall the frontier
+                let task = {
+                    let (url, state, config) = (url.clone(), state.clone(), config.clone());
+                    tokio::spawn(async move { crawl(&url, state, &config, depth).await })
+                };
+                let found = match task.await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("Crawling failed: {}: {}", url, e);
+                        vec![]
+                    }
+                };
+
+                let found_count = state.urls.lock().unwrap().len();
+                state.progress.found.store(found_count, Ordering::Relaxed);
+
+                let mut frontier = state.frontier.lock().unwrap();
+                let found = found.into_iter().map(|x| (x, depth + 1));
+                match config.strategy {
+                    Strategy::Bfs => frontier.queue.extend(found),
+                    // Reversed so the stack pops links in the order they appear on the page
+                    Strategy::Dfs => frontier.queue.extend(found.rev()),
+                }
+                if let Some(index) = frontier
+                    .active
+                    .iter()
+                    .position(|(x, x_depth)| *x == url && *x_depth == depth)
+                {
+                    frontier.active.remove(index);
+                }
+                state
+                    .progress
+                    .queued
+                    .store(frontier.queue.len(), Ordering::Relaxed);
+                state.wake.notify_waiters();
+            }
+            None => notified.await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(x: &str) -> Url {
+        Url::parse(x).unwrap()
+    }
+
+    fn normalized(x: &str, sort_query: bool) -> String {
+        normalize_url(&url(x), sort_query).to_string()
+    }
+
+    #[test]
+    fn normalize_lowercases_host() {
+        assert_eq!(
+            normalized("http://EXAMPLE.com/a", false),
+            "http://example.com/a"
+        );
+        assert_eq!(
+            normalized("foo://EXAMPLE.com/a", false),
+            "foo://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_removes_default_port() {
+        assert_eq!(
+            normalized("http://example.com:80/a", false),
+            "http://example.com/a"
+        );
+        assert_eq!(
+            normalized("https://example.com:443/a", false),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            normalized("http://example.com:8080/a", false),
+            "http://example.com:8080/a"
+        );
+    }
+
+    #[test]
+    fn normalize_removes_dot_segments() {
+        assert_eq!(
+            normalized("http://example.com/a/./b/../c", false),
+            "http://example.com/a/c"
+        );
+        assert_eq!(
+            normalized("http://example.com/a/%2e/b/%2E%2e/c", false),
+            "http://example.com/a/c"
+        );
+    }
+
+    #[test]
+    fn normalize_drops_trailing_slash() {
+        assert_eq!(
+            normalized("http://example.com/a/", false),
+            "http://example.com/a"
+        );
+        assert_eq!(
+            normalized("http://example.com/a//", false),
+            "http://example.com/a"
+        );
+        assert_eq!(
+            normalized("http://example.com/", false),
+            "http://example.com/"
+        );
+    }
+
+    #[test]
+    fn normalize_strips_fragment() {
+        assert_eq!(
+            normalized("http://example.com/a#section", false),
+            "http://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_sorts_query() {
+        assert_eq!(
+            normalized("http://example.com/a?b=2&a=1", true),
+            "http://example.com/a?a=1&b=2"
+        );
+        assert_eq!(
+            normalized("http://example.com/a?b=2&a=1", false),
+            "http://example.com/a?b=2&a=1"
+        );
+        assert_eq!(
+            normalized("http://example.com/a?", false),
+            "http://example.com/a"
+        );
+    }
+
+    #[test]
+    fn normalize_is_idempotent() {
+        for x in [
+            "http://EXAMPLE.com:80/a/./b/../c/?z=1&y=2#top",
+            "https://example.com/%2e%2e/a//",
+            "http://example.com/",
+            "foo://Example.com/a/",
+        ] {
+            for sort_query in [false, true] {
+                let once = normalize_url(&url(x), sort_query);
+                assert_eq!(normalize_url(&once, sort_query), once, "{}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn same_site_with_subdomains() {
+        assert!(same_site(
+            &url("https://blog.example.com/"),
+            &url("https://example.com/"),
+            true
+        ));
+        assert!(!same_site(
+            &url("https://blog.example.com/"),
+            &url("https://example.com/"),
+            false
+        ));
+    }
+
+    #[test]
+    fn same_site_compares_ips_exactly() {
+        assert!(!same_site(
+            &url("http://127.0.0.1/"),
+            &url("http://10.0.0.1/"),
+            true
+        ));
+        assert!(same_site(
+            &url("http://127.0.0.1:8080/"),
+            &url("http://127.0.0.1/"),
+            true
+        ));
+    }
+
+    #[test]
+    fn links_on_redirect_targets_are_external() {
+        let config = Config::new(url("http://127.0.0.1:8001/"));
+        let page = url("http://localhost:8002/");
+        assert_eq!(
+            skip_reason(
+                &page,
+                &url("http://localhost:8002/secret"),
+                true,
+                false,
+                1,
+                &config
+            ),
+            Some("Url is external")
+        );
+        assert_eq!(
+            skip_reason(
+                &page,
+                &url("http://127.0.0.1:8001/a"),
+                true,
+                false,
+                1,
+                &config
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use log::warn;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// File that workers append whole lines to
+#[derive(Debug)]
+pub struct LineWriter {
+    path: PathBuf,
+    file: Mutex<fs::File>,
+}
+
+impl LineWriter {
+    pub fn new(path: &Path, file: fs::File) -> LineWriter {
+        LineWriter {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        }
+    }
+
+    /// Appends `line` and a newline
+    pub fn write(&self, line: &str) {
+        let content = format!("{}\n", line);
+        // Written in one call so lines of concurrent workers don't interleave
+        if let Err(e) = self.file.lock().unwrap().write_all(content.as_bytes()) {
+            warn!("Cannot write to file: {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::{fs, path::Path};
+use url::Url;
+
+use crate::lines::LineWriter;
+
+/// Which found urls a [`LiveExport`] gets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    All,
+    Internal,
+    External,
+}
+
+/// File that found urls are written to one per line as soon as they are found, so they aren't
+/// lost if the crawl doesn't finish
+#[derive(Debug)]
+pub struct LiveExport {
+    scope: Scope,
+    writer: LineWriter,
+}
+
+impl LiveExport {
+    /// Creates the file, or truncates it if it exists
+    pub fn create(path: &Path, scope: Scope) -> Result<LiveExport, String> {
+        match fs::File::create(path) {
+            Ok(file) => Ok(LiveExport {
+                scope,
+                writer: LineWriter::new(path, file),
+            }),
+            Err(e) => Err(format!("Cannot create file: {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Writes `url` if it is in the scope of this export
+    pub fn write(&self, url: &Url, internal: bool) {
+        let included = match self.scope {
+            Scope::All => true,
+            Scope::Internal => internal,
+            Scope::External => !internal,
+        };
+        if included {
+            self.writer.write(url.as_str());
+        }
+    }
+}
@@ -1,43 +1,203 @@
-use clap::Parser;
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches, Parser};
 use colored::Colorize;
-use log::{debug, error, info, trace, warn};
-use reqwest::header::HeaderMap;
+use indicatif::ProgressBar;
+use log::{error, info, trace, warn};
+use regex::Regex;
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION};
+use serde::Serialize;
 use std::time;
 use std::{
     borrow::Borrow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
     fs,
-    io::Write,
-    path::Path,
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
-    thread,
+    sync::{atomic::Ordering, Arc},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
 };
 use url::Url;
 
+use web_crawler::{
+    document::Metadata, live::Scope, sitemap, CrawlResult, Crawler, Credentials, Progress,
+    Strategy, USER_AGENT,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum Politeness {
+    Gentle,
+    Normal,
+    Aggressive,
+}
+
+impl Politeness {
+    /// Sets --timeout, --concurrency, --retries and --retry-backoff, unless they were given, and
+    /// how many requests are made to the same host at a time
+    fn apply(self, args: &mut Args, matches: &clap::ArgMatches) {
+        let (timeout, concurrency, per_host_concurrency, retries, retry_backoff) = match self {
+            Politeness::Gentle => (1000, 1, 1, 2, 2000),
+            Politeness::Normal => (100, 8, 4, 2, 500),
+            Politeness::Aggressive => (0, 32, 16, 1, 200),
+        };
+        let (timeout, retry_backoff) = (
+            time::Duration::from_millis(timeout),
+            time::Duration::from_millis(retry_backoff),
+        );
+        let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if !given("timeout") {
+            args.timeout = timeout;
+        }
+        if !given("concurrency") {
+            args.concurrency = concurrency;
+        }
+        args.per_host_concurrency = Some(per_host_concurrency);
+        if !given("retries") {
+            args.retries = retries;
+        }
+        if !given("retry_backoff") {
+            args.retry_backoff = retry_backoff;
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
 /// Rust Web Crawler
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     // /// Url of the website you want to crawl
     // #[arg(short, long)]
-    url: String,
+    /// Url to start from, or - to read urls from stdin, one per line
+    #[arg(required_unless_present_any = ["seeds_file", "config"])]
+    url: Option<String>,
+
+    /// Also start from the urls in this file, one per line. Pages on the hosts of all seeds are internal
+    #[arg(long, value_name = "FILE")]
+    seeds_file: Option<PathBuf>,
+
+    /// Load options from a JSON object with keys named like the flags, e.g. {"url": "https://example.com", "max-depth": 3, "exclude": ["/blog"]}. Flags that are given replace the values from the file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
 
     /// Download all files
     #[arg(short, long)]
     download: bool,
 
+    /// Where to save downloaded files
+    #[arg(short, long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Replace files that were already downloaded instead of skipping them
+    #[arg(long)]
+    overwrite: bool,
+
+    /// Write the status line and headers of every downloaded file to a .headers file next to it
+    #[arg(long, requires = "download")]
+    save_headers: bool,
+
+    /// Point links in downloaded html pages to the downloaded files once the crawl is done, so the site can be browsed offline
+    #[arg(long, requires = "download")]
+    mirror: bool,
+
+    /// Ask the server whether already downloaded files changed (If-Modified-Since) and reuse them if not
+    #[arg(long)]
+    if_modified: bool,
+
+    /// Don't request files that were downloaded to the output directory before, as listed in its .web-crawler-downloaded file. Pages are still fetched to find their links
+    #[arg(long, requires = "download", conflicts_with = "overwrite")]
+    skip_downloaded: bool,
+
+    /// Only save files with these content types, e.g. image/png,application/pdf or image/* (comma-seperated)
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    download_types: Vec<String>,
+
+    /// Don't download files larger than this many bytes [default: unlimited]
+    #[arg(long)]
+    max_file_size: Option<u64>,
+
+    /// Read at most this many bytes per second, summed over all workers [default: unlimited]
+    #[arg(long, value_name = "BYTES_PER_SECOND")]
+    max_bandwidth: Option<u64>,
+
+    /// Send a HEAD request before each GET and skip the GET for files that are neither parsed for links nor saved, or too large. Falls back to GET if the server doesn't support HEAD
+    #[arg(long)]
+    head_first: bool,
+
     /// Whether or not to crawl other websites it finds a link to. Might result in downloading the entire internet
     #[arg(short, long)]
     crawl_external: bool,
 
+    /// Treat subdomains of the seed's registrable domain, like blog.example.com for example.com, as internal
+    #[arg(long)]
+    include_subdomains: bool,
+
+    /// Count this domain and its subdomains as internal instead of the hosts of the seeds (repeatable)
+    #[arg(long = "internal-domain", value_name = "DOMAIN")]
+    internal_domains: Vec<String>,
+
+    /// Also crawl this domain and its subdomains (repeatable)
+    #[arg(long = "allow-domain", value_name = "DOMAIN")]
+    allow_domains: Vec<String>,
+
+    /// Never crawl this domain or its subdomains, even with --crawl-external (repeatable)
+    #[arg(long = "deny-domain", value_name = "DOMAIN")]
+    deny_domains: Vec<String>,
+
     /// Maximum url length it allows. Will ignore page it url length reaches this limit
     #[arg(short, long, default_value_t = 300)]
     max_url_length: u32,
 
+    /// Don't crawl urls with more path segments than this, e.g. from endless calendars. Urls whose path segments repeat three times in a row, like /a/a/a, are never crawled [default: unlimited]
+    #[arg(long)]
+    max_path_segments: Option<usize>,
+
     /// Will ignore paths that start with these strings (comma-seperated)
     #[arg(short, long, use_value_delimiter = true, value_delimiter = ',')]
     exclude: Vec<String>,
 
+    /// Won't crawl urls matching this regex. Takes precedence over --include-regex (repeatable)
+    #[arg(long, value_parser = Regex::new)]
+    exclude_regex: Vec<Regex>,
+
+    /// Only crawl urls matching this regex (repeatable)
+    #[arg(long, value_parser = Regex::new)]
+    include_regex: Vec<Regex>,
+
+    /// Only crawl urls matching a pattern in this file, like --include-regex (repeatable). One pattern per line, see --exclude-from
+    #[arg(long, value_name = "FILE")]
+    include_from: Vec<PathBuf>,
+
+    /// Won't crawl urls matching a pattern in this file, like --exclude-regex (repeatable). One glob per line, matched against the path if it starts with / and against the url otherwise, or a regex after "regex:". Empty lines and lines starting with # are ignored
+    #[arg(long, value_name = "FILE")]
+    exclude_from: Vec<PathBuf>,
+
+    /// Only fetch urls with these file extensions, e.g. html,php. Urls without an extension are always fetched (comma-seperated)
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    allow_ext: Vec<String>,
+
+    /// Never fetch urls with these file extensions, e.g. jpg,png,css. Takes precedence over --allow-ext (comma-seperated)
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    deny_ext: Vec<String>,
+
     /// Where to export found URLs
     #[arg(long)]
     export: Option<String>,
@@ -50,332 +210,1391 @@ struct Args {
     #[arg(long)]
     export_external: Option<String>,
 
-    /// Timeout between requests in milliseconds
-    #[arg(short, long, default_value_t = 100)]
-    timeout: u64,
+    /// Write --export, --export-internal and --export-external while crawling, one url per line as each is found, so a crash doesn't lose them. They are rewritten in --format when the crawl ends
+    #[arg(long)]
+    export_live: bool,
+
+    /// Timeout between requests to the same host, e.g. 500ms or 2s. A bare number is in milliseconds
+    #[arg(short, long, default_value = "100ms", value_parser = parse_millis)]
+    timeout: time::Duration,
+
+    /// Preset for --timeout, --concurrency, --retries, --retry-backoff and how many requests are made to the same host at a time. gentle makes one request per second to each host with one worker, normal makes up to 4 requests to a host at a time, aggressive doesn't wait and uses 32 workers with up to 16 per host. Flags that are given override it
+    #[arg(long, value_enum)]
+    politeness: Option<Politeness>,
+
+    /// Add a random delay of up to this long to each wait between requests, so they look less regular. A bare number is in milliseconds
+    #[arg(long, default_value = "0ms", value_parser = parse_millis)]
+    delay_jitter: time::Duration,
+
+    /// Don't fetch or obey robots.txt or X-Robots-Tag nofollow headers. Crawl-delay directives override --timeout otherwise
+    #[arg(long)]
+    ignore_robots: bool,
+
+    /// Maximum link depth to follow from the seed. 0 only crawls the seed url [default: unlimited]
+    #[arg(long)]
+    max_depth: Option<u32>,
+
+    /// Stop discovering new urls once this many have been found [default: unlimited]
+    #[arg(long)]
+    max_pages: Option<usize>,
+
+    /// Stop following links into a host once this many of its urls were queued, so one big site can't use up a broad crawl [default: unlimited]
+    #[arg(long)]
+    per_host_max_pages: Option<usize>,
+
+    /// Stop following links to new hosts once urls of this many hosts were queued, seeds included. Hosts that were already reached are still crawled [default: unlimited]
+    #[arg(long)]
+    max_hosts: Option<usize>,
+
+    /// Stop the crawl after this long, e.g. 90s or 1h, and output what was found so far, like Ctrl-C. A bare number is in seconds [default: unlimited]
+    #[arg(long, value_parser = parse_secs)]
+    max_time: Option<time::Duration>,
+
+    /// User-Agent header sent with every request
+    #[arg(long, default_value_t = USER_AGENT.to_string())]
+    user_agent: String,
+
+    /// Don't crawl links marked rel="nofollow". They are still recorded
+    #[arg(long)]
+    respect_nofollow: bool,
+
+    /// Only crawl internal urls under the seed url's path
+    #[arg(long)]
+    same_path: bool,
+
+    /// Timeout for each request, e.g. 30s. A bare number is in milliseconds
+    #[arg(long, default_value = "30s", value_parser = parse_millis)]
+    request_timeout: time::Duration,
+
+    /// Where to export the link graph. Written as DOT, or as an adjacency list with --format json
+    #[arg(long)]
+    export_graph: Option<String>,
+
+    /// How many times to retry a request after a network error or 5xx response
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+
+    /// Delay before the first retry, e.g. 500ms. Doubles with every attempt. A bare number is in milliseconds
+    #[arg(long, default_value = "500ms", value_parser = parse_millis)]
+    retry_backoff: time::Duration,
+
+    /// Where to export urls that couldn't be fetched along with the error
+    #[arg(long)]
+    export_failed: Option<String>,
+
+    /// Where to export urls that weren't crawled because of robots.txt along with the Disallow rule that matched
+    #[arg(long)]
+    export_disallowed: Option<String>,
+
+    /// Where to export the http status code of every fetched url
+    #[arg(long)]
+    export_status: Option<String>,
+
+    /// Where to write a sitemap.xml of all reachable internal urls
+    #[arg(long)]
+    export_sitemap: Option<String>,
+
+    /// Check every link, including external ones, and report the ones that are broken. External pages are fetched but not crawled
+    #[arg(long)]
+    check_links: bool,
+
+    /// Where to export broken links found with --check-links
+    #[arg(long)]
+    export_broken: Option<String>,
+
+    /// Print groups of internal pages that share the same title or meta description
+    #[arg(long)]
+    report_duplicates: bool,
+
+    /// Print the pages with more than this many distinct links, e.g. link farms or bloated navigation, with their link count, most links first
+    #[arg(long, value_name = "THRESHOLD")]
+    report_link_count: Option<usize>,
+
+    /// Number of workers crawling in parallel, which bounds concurrent requests
+    #[arg(long, visible_alias = "workers", default_value_t = 8)]
+    concurrency: usize,
+
+    /// Set by --politeness
+    #[arg(skip)]
+    per_host_concurrency: Option<usize>,
+
+    /// Crawl one url at a time in --strategy order, so the same site always gives the same result, even with --max-pages. Overrides --concurrency
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Crawl breadth-first (bfs), finishing each depth before going deeper, or depth-first (dfs), following one branch as far as it goes
+    #[arg(long, value_name = "bfs|dfs", default_value = "bfs")]
+    strategy: Strategy,
+
+    /// Seed the crawl with the urls from the seed host's sitemap.xml, following sitemap indexes
+    #[arg(long)]
+    use_sitemap: bool,
+
+    /// Only fetch the seed and print each of its links with whether it would be crawled, and why not. Nothing is downloaded
+    #[arg(long, conflicts_with = "state_file")]
+    dry_run: bool,
+
+    /// Send every request through this HTTP or SOCKS5 proxy, e.g. socks5://localhost:1080. Uses HTTP_PROXY/HTTPS_PROXY if not given
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Don't verify TLS certificates, e.g. to crawl staging servers with self-signed ones. Only use this on networks you trust
+    #[arg(long)]
+    insecure: bool,
+
+    /// PEM file with a client certificate for sites that require mutual TLS
+    #[arg(long, value_name = "PEM", requires = "client_key")]
+    client_cert: Option<PathBuf>,
+
+    /// PEM file with the PKCS #8 private key of --client-cert
+    #[arg(long, value_name = "PEM", requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    /// Only connect over IPv4
+    #[arg(long, conflicts_with = "ipv6_only")]
+    ipv4_only: bool,
+
+    /// Only connect over IPv6
+    #[arg(long)]
+    ipv6_only: bool,
+
+    /// Connect to HOST at IP instead of looking it up, like curl's --resolve, e.g. example.com:127.0.0.1 (repeatable)
+    #[arg(long, value_name = "HOST:IP", value_parser = parse_resolve)]
+    resolve: Vec<(String, IpAddr)>,
+
+    /// Extra header sent with every request, e.g. "Authorization: Bearer token" (repeatable)
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// Send the page each url was found on as the Referer header. Only sent to the same origin unless --cross-origin-referer is given
+    #[arg(long)]
+    send_referer: bool,
+
+    /// With --send-referer, send just the origin of the page to other origins too. Never sent from https to http
+    #[arg(long, requires = "send_referer")]
+    cross_origin_referer: bool,
+
+    /// Cookie sent to the seed host as name=value (repeatable). Cookies set by the server are kept for later requests
+    #[arg(long = "cookie", value_name = "COOKIE")]
+    cookies: Vec<String>,
+
+    /// Load cookies from a Netscape-format cookie file, like the ones curl writes
+    #[arg(long)]
+    cookie_file: Option<PathBuf>,
+
+    /// Basic auth credentials sent to the seed host. Never logged
+    #[arg(long, value_name = "USER:PASS")]
+    auth: Option<Credentials>,
+
+    /// Maximum number of redirects followed for a single request
+    #[arg(long, default_value_t = 10)]
+    max_redirects: usize,
+
+    /// Don't follow redirects, so their 3xx status shows up. The redirect target is crawled like a link instead
+    #[arg(long)]
+    no_redirects: bool,
+
+    /// Treat pages with the same <link rel="canonical"> as one page and report them by their canonical url
+    #[arg(long)]
+    dedupe_canonical: bool,
+
+    /// Treat urls that only differ in the order of their query parameters as the same url
+    #[arg(long)]
+    sort_query: bool,
+
+    /// Keep query strings of found urls instead of stripping them, so pagination like ?page=2 is followed. Fragments are always stripped
+    #[arg(long)]
+    keep_query: bool,
+
+    /// Replace downloaded files with the same content as an earlier one with a symlink to it
+    #[arg(long)]
+    dedupe_content: bool,
+
+    /// Save the progress of the crawl to this file every 30 seconds and when it ends. The crawl is resumed from it if it exists
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Remember the ETag and Last-Modified headers, links and metadata of fetched urls in this file, and ask on later crawls whether they changed (If-None-Match/If-Modified-Since). Unchanged urls aren't downloaded or parsed again
+    #[arg(long, value_name = "FILE")]
+    http_cache: Option<PathBuf>,
+
+    /// Append a line of json to this file for every fetched page, found url, saved download and error, e.g. to watch a crawl with tail -f
+    #[arg(long, value_name = "FILE")]
+    events: Option<PathBuf>,
+
+    /// Don't follow links of pages with a <meta name="robots" content="nofollow"> tag
+    #[arg(long)]
+    respect_robots_meta: bool,
+
+    /// Leave pages marked noindex in a robots meta tag or X-Robots-Tag header out of the found urls and exports
+    #[arg(long, requires = "respect_robots_meta")]
+    exclude_noindex: bool,
+
+    /// Look for urls in string literals of inline scripts and JavaScript files. Heuristic, so it may find urls that don't exist
+    #[arg(long)]
+    scan_js: bool,
+
+    /// Only follow <a> links of pages and ignore images, scripts, stylesheets and other assets, for a map of just the pages
+    #[arg(long, conflicts_with = "scan_js")]
+    html_only: bool,
+
+    /// Only fetch images, scripts and other assets that are on the same site as the page that loads them. Links between pages are still followed as usual, e.g. with --crawl-external
+    #[arg(long)]
+    first_party_assets: bool,
+
+    /// Only print the summary instead of every found url
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Show a live count of fetched, found and queued urls. Lowers the default log level to warn
+    #[arg(long)]
+    progress: bool,
+
+    /// Serve the progress of the crawl as json at http://<status-host>:<port>/status
+    #[arg(long, value_name = "PORT")]
+    status_port: Option<u16>,
+
+    /// Address the status server listens on. Use 0.0.0.0 to reach it from other machines, e.g. from outside a container
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        default_value = "127.0.0.1",
+        requires = "status_port"
+    )]
+    status_host: IpAddr,
+
+    /// Also write logs to this file. Appended to if it exists. Logs at info level unless RUST_LOG is set, even with --progress
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Format of log messages. json writes one object per line with the timestamp, level, target and message
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Format of exported files. json writes an array of objects with the url, whether it is internal, the page it was first found on and its title, description and language. csv writes a header row, and url exports get the status, depth and first page each url was found on too
+    #[arg(long, value_enum, default_value_t = Format::Text)]
+    format: Format,
 }
 
-fn get_urls_from_document(doc: &str) -> Result<Vec<String>, String> {
-    let mut found = Vec::new();
+/// Turns a glob, where `*` matches anything and `?` any character, into a regex matching whole
+/// urls. Globs starting with `/` are matched against the path
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut pattern = match glob.starts_with('/') {
+        true => "^[^:/?#]+://[^/?#]*".to_string(),
+        false => "^".to_string(),
+    };
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern)
+}
 
-    debug!("Parsing html...");
-    let dom = match tl::parse(doc, tl::ParserOptions::default()) {
+/// Loads a pattern file for --include-from or --exclude-from
+fn load_patterns(path: &PathBuf) -> Result<Vec<Regex>, String> {
+    let content = match fs::read_to_string(path) {
         Ok(x) => x,
         Err(e) => {
-            return Err(format!("Cannot parse html: {}", e));
+            return Err(format!("Cannot read file: {}: {}", path.display(), e));
         }
     };
 
-    trace!("Looping over all elements...");
-    for element in dom.nodes().iter() {
-        let tag = match element.as_tag() {
-            Some(x) => x,
-            None => {
-                continue;
-            }
-        };
-
-        let value = match match tag.attributes().get("href") {
-            Some(x) => x,
-            None => match tag.attributes().get("src") {
-                Some(x) => x,
-                None => continue,
-            },
-        } {
-            Some(x) => x,
-            None => continue,
+    let mut patterns = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let pattern = match line.strip_prefix("regex:") {
+            Some(x) => Regex::new(x.trim()),
+            None => glob_to_regex(line),
         };
-        trace!("Found link: {}", value.as_utf8_str().to_string());
-
-        found.push(value.as_utf8_str().to_string());
+        match pattern {
+            Ok(x) => patterns.push(x),
+            Err(e) => {
+                return Err(format!(
+                    "Invalid pattern: {}:{}: {}",
+                    path.display(),
+                    number + 1,
+                    e
+                ));
+            }
+        }
     }
-
-    Ok(found)
+    Ok(patterns)
 }
 
-fn is_html(headers: &HeaderMap) -> Result<bool, String> {
-    match headers.get("content-type") {
-        Some(content_type) => match content_type.to_str() {
-            Ok(content_type_string) => match content_type_string.split(';').next() {
-                Some(x) => Ok(x == "text/html"),
-                None => Err("content-type header is empty".to_string()),
-            },
-            Err(_) => Err("Cannot stringify content-type header".to_string()),
-        },
-        None => Err("Response header doesn't have content-type".to_string()),
-    }
+/// Parses the command line `cli` with the options of its `--config` file. The command line alone
+/// isn't validated, since the file may give the arguments that its flags require
+fn parse_matches(cli: Vec<OsString>) -> Result<clap::ArgMatches, clap::Error> {
+    let matches = Args::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&cli)?;
+    let config = match matches.get_one::<PathBuf>("config") {
+        Some(x) => x,
+        None => return Args::command().try_get_matches_from(cli),
+    };
+    let file_args = config_args(config, &matches)
+        .map_err(|e| Args::command().error(clap::error::ErrorKind::InvalidValue, e))?;
+
+    let mut cli = cli.into_iter();
+    let program = cli.next().unwrap_or_default();
+    Args::command().try_get_matches_from(
+        std::iter::once(program)
+            .chain(file_args.into_iter().map(OsString::from))
+            .chain(cli),
+    )
 }
 
-fn save_document(url: &Url, is_html: bool, content: &[u8]) -> Result<(), String> {
-    trace!("Downloading file...");
-    let mut path = match std::env::current_dir() {
+/// Turns the options in a JSON config file into command line arguments. Options that were
+/// given on the command line are skipped, so they replace the file's values
+fn config_args(path: &Path, matches: &clap::ArgMatches) -> Result<Vec<String>, String> {
+    let content = match fs::read_to_string(path) {
         Ok(x) => x,
         Err(e) => {
-            return Err(format!("Cannot get current directory: {}", e));
+            return Err(format!(
+                "Cannot read config file: {}: {}",
+                path.display(),
+                e
+            ));
         }
     };
-    path.push(match url.host_str() {
-        Some(x) => x,
-        None => {
-            return Err("Cannot get host".to_string());
+    let options: serde_json::Map<String, serde_json::Value> = match serde_json::from_str(&content) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Invalid config file: {}: {}", path.display(), e));
         }
-    });
+    };
 
-    let path2 = path.clone();
-    let path_string = match path2.to_str() {
-        Some(x) => x,
-        None => {
-            return Err("Couldn't stringify path".to_string());
+    let command = Args::command();
+    let (mut flags, mut positionals) = (vec![], vec![]);
+    for (key, value) in options {
+        // Keys are named like the flags, but positionals and some flags only have their id
+        let id = key.replace('-', "_");
+        let arg = match command
+            .get_arguments()
+            .find(|x| x.get_long() == Some(key.as_str()))
+            .or_else(|| command.get_arguments().find(|x| x.get_id() == id.as_str()))
+        {
+            Some(x) if x.get_id() != "config" => x,
+            _ => return Err(format!("Unknown option in config file: {}", key)),
+        };
+        if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+            continue;
         }
-    };
 
-    {
-        let mut relative_path = url.path().strip_prefix('/').unwrap_or(url.path());
-        relative_path = relative_path.strip_suffix('/').unwrap_or(relative_path);
-        relative_path = relative_path.strip_suffix('\\').unwrap_or(relative_path);
-        trace!("Working directory: {}", path_string);
-        path.push(relative_path);
+        let values = match value {
+            serde_json::Value::Array(x) => x,
+            x => vec![x],
+        };
+        for value in values {
+            let value = match value {
+                serde_json::Value::String(x) => x,
+                serde_json::Value::Number(x) => x.to_string(),
+                serde_json::Value::Bool(x) => x.to_string(),
+                _ => return Err(format!("Invalid value in config file: {}", key)),
+            };
+            match arg.get_long() {
+                // Flags are only given if they are true
+                Some(long) if matches!(arg.get_action(), clap::ArgAction::SetTrue) => {
+                    if value == "true" {
+                        flags.push(format!("--{}", long));
+                    }
+                }
+                Some(long) => flags.push(format!("--{}={}", long, value)),
+                None => positionals.push(value),
+            }
+        }
     }
 
-    if is_html && !path.ends_with(".html") {
-        path.push("index.html");
+    flags.extend(positionals);
+    Ok(flags)
+}
+
+/// Parses a duration like `500ms`, `2s` or `1m 30s`. Bare numbers are in the unit of `from_number`
+fn parse_duration(
+    duration: &str,
+    from_number: fn(u64) -> time::Duration,
+) -> Result<time::Duration, String> {
+    if let Ok(x) = duration.trim().parse() {
+        return Ok(from_number(x));
     }
-    let path2 = path.clone();
-    let path_string = match path2.to_str() {
+    match humantime::parse_duration(duration) {
+        Ok(x) => Ok(x),
+        Err(e) => Err(format!(
+            "Expected a duration like \"500ms\" or \"2s\": {}",
+            e
+        )),
+    }
+}
+
+fn parse_millis(duration: &str) -> Result<time::Duration, String> {
+    parse_duration(duration, time::Duration::from_millis)
+}
+
+fn parse_secs(duration: &str) -> Result<time::Duration, String> {
+    parse_duration(duration, time::Duration::from_secs)
+}
+
+/// Parses a `--resolve` override like `example.com:127.0.0.1` or `example.com:[::1]`
+fn parse_resolve(resolve: &str) -> Result<(String, IpAddr), String> {
+    let (host, address) = match resolve.split_once(':') {
         Some(x) => x,
         None => {
-            return Err("Couldn't stringify path".to_string());
+            return Err("Expected an override like \"example.com:127.0.0.1\"".to_string());
         }
     };
-    trace!("Location before: {}", path_string);
-    let mut path_without_last_dir = path.clone();
-    assert!(path_without_last_dir.pop());
-    let path_without_last_dir_string = match path_without_last_dir.to_str() {
+    let address = address.trim_start_matches('[').trim_end_matches(']');
+    match address.parse() {
+        Ok(x) => Ok((host.to_string(), x)),
+        Err(e) => Err(format!("Invalid IP address: {}: {}", address, e)),
+    }
+}
+
+/// Parses a `Name: Value` header
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = match header.split_once(':') {
         Some(x) => x,
         None => {
-            return Err("Couldn't stringify path".to_string());
+            return Err("Expected a header like \"Name: Value\"".to_string());
         }
     };
-    trace!("Creating directories: {}", path_without_last_dir_string);
-    if let Err(e) = fs::create_dir_all(&path_without_last_dir) {
-        return Err(format!(
-            "Cannot create directory: {}: {}",
-            path_without_last_dir_string, e
-        ));
-    }
-    {
-        let mut file_path = path_string;
-        file_path = file_path.strip_suffix('/').unwrap_or(file_path);
-        file_path = file_path.strip_suffix('\\').unwrap_or(file_path);
-
-        if Path::new(file_path).exists() {
-            return Err(format!("File already exists: {}", file_path));
-        }
-        trace!("Writing to file: {}", file_path);
-        let mut f = match fs::File::create(file_path) {
-            Ok(x) => x,
-            Err(e) => {
-                return Err(format!("Cannot create file: {}: {}", file_path, e));
-            }
+    let name = match HeaderName::from_bytes(name.trim().as_bytes()) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Invalid header name: {}: {}", name.trim(), e));
+        }
+    };
+    let mut value = match HeaderValue::from_str(value.trim()) {
+        Ok(x) => x,
+        Err(e) => {
+            return Err(format!("Invalid header value: {}: {}", name, e));
+        }
+    };
+    // Keeps secrets out of the logs
+    if matches!(name, AUTHORIZATION | PROXY_AUTHORIZATION | COOKIE) {
+        value.set_sensitive(true);
+    }
+    Ok((name, value))
+}
+
+#[derive(Serialize)]
+struct ExportEntry<'a> {
+    url: &'a str,
+    internal: bool,
+    /// Page the url was first found on
+    found_on: Option<&'a str>,
+    title: Option<&'a str>,
+    description: Option<&'a str>,
+    lang: Option<&'a str>,
+}
+
+/// A row of a csv url export. Fields that weren't tracked for a url are left empty
+#[derive(Serialize)]
+struct CsvEntry<'a> {
+    url: &'a str,
+    internal: bool,
+    status: Option<u16>,
+    depth: Option<u32>,
+    found_on: Option<&'a str>,
+    title: Option<&'a str>,
+    description: Option<&'a str>,
+    lang: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct EdgeEntry<'a> {
+    from: &'a str,
+    to: &'a str,
+}
+
+#[derive(Serialize)]
+struct StatusEntry<'a> {
+    url: &'a str,
+    status: u16,
+}
+
+/// A link that returned an error status or couldn't be fetched
+#[derive(Serialize)]
+struct BrokenLink<'a> {
+    url: &'a str,
+    status: Option<u16>,
+    error: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct BrokenEntry<'a> {
+    page: &'a str,
+    url: &'a str,
+    status: Option<u16>,
+    error: Option<&'a str>,
+}
+
+/// Where `url` ended up, since statuses are recorded under the url a redirect led to. Redirects
+/// are followed within one request, so a single lookup gives the final url
+fn resolve_redirects<'a>(url: &'a Url, redirects: &'a BTreeMap<Url, Url>) -> &'a Url {
+    redirects.get(url).unwrap_or(url)
+}
+
+/// Groups broken links by the page they were found on
+fn broken_links<'a>(
+    edges: &'a [(Url, Url)],
+    statuses: &BTreeMap<Url, u16>,
+    failed: &'a [(Url, String)],
+    redirects: &BTreeMap<Url, Url>,
+) -> BTreeMap<&'a str, Vec<BrokenLink<'a>>> {
+    let mut broken: BTreeMap<&str, Vec<BrokenLink>> = BTreeMap::new();
+    for (from, to) in edges {
+        let target = resolve_redirects(to, redirects);
+        let link = match statuses.get(target) {
+            Some(status) if *status >= 400 => BrokenLink {
+                url: to.as_str(),
+                status: Some(*status),
+                error: None,
+            },
+            Some(_) => continue,
+            None => match failed.iter().find(|(url, _)| url == to || url == target) {
+                Some((_, error)) => BrokenLink {
+                    url: to.as_str(),
+                    status: None,
+                    error: Some(error),
+                },
+                None => continue,
+            },
         };
+        broken.entry(from.as_str()).or_default().push(link);
+    }
+    broken
+}
 
-        match f.write_all(content) {
-            Ok(_) => {}
+#[derive(Serialize)]
+struct ProgressEntry {
+    fetched: usize,
+    found: usize,
+    queued: usize,
+    downloads: usize,
+    bytes: u64,
+    errors: usize,
+    elapsed_secs: f64,
+}
+
+/// Answers every request for `/status` with the current progress of the crawl
+async fn serve_status(listener: TcpListener, progress: Arc<Progress>, start: time::Instant) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((x, _)) => x,
             Err(e) => {
-                return Err(format!("Cannot write to file: {}: {}", file_path, e));
+                warn!("Cannot accept status connection: {}", e);
+                continue;
             }
         };
+        let progress = progress.clone();
+        tokio::spawn(async move {
+            // Only the request line matters, which fits in the first read
+            let mut request = [0; 1024];
+            let length = match stream.read(&mut request).await {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            let request = String::from_utf8_lossy(&request[..length]);
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+
+            let (status, body) = match path.split('?').next() {
+                Some("/status") => {
+                    let entry = ProgressEntry {
+                        fetched: progress.fetched.load(Ordering::Relaxed),
+                        found: progress.found.load(Ordering::Relaxed),
+                        queued: progress.queued.load(Ordering::Relaxed),
+                        downloads: progress.downloads.load(Ordering::Relaxed),
+                        bytes: progress.bytes.load(Ordering::Relaxed),
+                        errors: progress.errors.load(Ordering::Relaxed),
+                        elapsed_secs: start.elapsed().as_secs_f64(),
+                    };
+                    ("200 OK", serde_json::to_string(&entry).unwrap_or_default())
+                }
+                _ => ("404 Not Found", r#"{"error":"not found"}"#.to_string()),
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                trace!("Cannot write status response: {}", e);
+            }
+        });
     }
+}
 
-    Ok(())
+#[derive(Serialize)]
+struct FailedEntry<'a> {
+    url: &'a str,
+    error: &'a str,
 }
 
-fn crawl(
-    url: &Url,
-    urls: Arc<Mutex<Vec<Url>>>,
-    args: &Args,
-    latest_request: Arc<Mutex<time::Instant>>,
-) {
-    {
-        let mut urls = urls.lock().unwrap();
+#[derive(Serialize)]
+struct DisallowedEntry<'a> {
+    url: &'a str,
+    rule: &'a str,
+}
 
-        if !urls.iter().any(|x| x.as_str() == url.as_str()) {
-            urls.push(url.clone());
-        }
-        if url.to_string().len() > args.max_url_length as usize {
-            warn!("URL too long: {}", url);
-            return;
-        }
+/// Writes records as csv with a header row taken from their field names
+fn to_csv<T: Serialize>(records: impl IntoIterator<Item = T>) -> Result<String, String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        writer.serialize(record).map_err(|e| e.to_string())?;
     }
+    let bytes = writer.into_inner().map_err(|e| e.to_string())?;
+    String::from_utf8(bytes).map_err(|e| e.to_string())
+}
 
-    // Wait for timeout
-    {
-        let mut latest_request = latest_request.lock().unwrap();
-        let time_since_last_request = latest_request.elapsed();
-        if time_since_last_request < time::Duration::from_millis(args.timeout) {
-            thread::sleep(time::Duration::from_millis({
-                let time = args.timeout - time_since_last_request.as_millis() as u64;
-                debug!("Sleeping for {}ms", time);
-                time
-            }));
-        }
-
-        *latest_request = time::Instant::now();
+/// Records in `format`: a `line` for each record as text, or the `entry` of each record as json
+/// or csv
+fn format_records<T, E: Serialize>(
+    records: impl Iterator<Item = T>,
+    format: Format,
+    line: impl Fn(T) -> String,
+    entry: impl Fn(T) -> E,
+) -> Result<String, String> {
+    match format {
+        Format::Text => Ok(records.map(line).collect()),
+        Format::Json => serde_json::to_string_pretty(&records.map(entry).collect::<Vec<_>>())
+            .map_err(|e| e.to_string()),
+        Format::Csv => to_csv(records.map(entry)),
     }
-    trace!("Fetching url: {}", url.to_string());
-    let response = match reqwest::blocking::get(url.as_str()) {
+}
+
+/// Writes the `content` of an export of `what` to `file_name`, logging if that fails
+fn write_export(file_name: &str, what: &str, content: Result<String, String>) {
+    let content = match content {
         Ok(x) => x,
         Err(e) => {
-            error!("Cannot request file: {}", e);
+            error!("Cannot serialize {}: {}", what, e);
             return;
         }
     };
-    let is_html = match is_html(response.headers()) {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("Cannot tell if document is html: {}", e);
-            return;
+    match fs::write(file_name, content) {
+        Ok(_) => info!("Exported {} to file: {}", what, file_name),
+        Err(e) => error!("Cannot write to file: {}: {}", file_name, e),
+    }
+}
+
+fn export_urls<T: Borrow<Url>>(file_name: &str, urls: &[T], format: Format, result: &CrawlResult) {
+    let urls = urls.iter().map(|x| x.borrow());
+    let internal = |url: &Url| result.internal.binary_search(url).is_ok();
+    let found_on = |url: &Url| result.referrers.get(url).map(|x| x.as_str());
+    // The csv export has more columns, since json entries can be joined with the other exports
+    let content = match format {
+        Format::Csv => to_csv(urls.map(|url| {
+            let metadata = result.metadata.get(url);
+            CsvEntry {
+                url: url.as_str(),
+                internal: internal(url),
+                status: result
+                    .statuses
+                    .get(resolve_redirects(url, &result.redirects))
+                    .copied(),
+                depth: result.depths.get(url).copied(),
+                found_on: found_on(url),
+                title: metadata.and_then(|x| x.title.as_deref()),
+                description: metadata.and_then(|x| x.description.as_deref()),
+                lang: metadata.and_then(|x| x.lang.as_deref()),
+            }
+        })),
+        _ => format_records(
+            urls,
+            format,
+            |url| format!("{}\n", url.as_str()),
+            |url| {
+                let metadata = result.metadata.get(url);
+                ExportEntry {
+                    url: url.as_str(),
+                    internal: internal(url),
+                    found_on: found_on(url),
+                    title: metadata.and_then(|x| x.title.as_deref()),
+                    description: metadata.and_then(|x| x.description.as_deref()),
+                    lang: metadata.and_then(|x| x.lang.as_deref()),
+                }
+            },
+        ),
+    };
+    write_export(file_name, "urls", content);
+}
+
+/// Exports the links between pages. As text, this is a Graphviz digraph, and as json a map from
+/// each page to the urls it links to
+fn export_graph(file_name: &str, edges: &[(Url, Url)], format: Format) {
+    let content = match format {
+        Format::Text => {
+            let mut content = "digraph {\n".to_string();
+            for (from, to) in edges {
+                content.push_str(&format!("    {:?} -> {:?};\n", from.as_str(), to.as_str()));
+            }
+            content.push_str("}\n");
+            Ok(content)
+        }
+        Format::Json => {
+            let mut adjacency: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+            for (from, to) in edges {
+                adjacency
+                    .entry(from.as_str())
+                    .or_default()
+                    .push(to.as_str());
+            }
+            serde_json::to_string_pretty(&adjacency).map_err(|e| e.to_string())
         }
+        Format::Csv => to_csv(edges.iter().map(|(from, to)| EdgeEntry {
+            from: from.as_str(),
+            to: to.as_str(),
+        })),
     };
-    let response_bytes = match response.bytes() {
-        Ok(x) => x,
-        Err(e) => {
-            warn!("Cannot parse response as text: {}: {}", url, e);
-            return;
+    write_export(file_name, "link graph", content);
+}
+
+fn export_failed(file_name: &str, failed: &[(Url, String)], format: Format) {
+    let content = format_records(
+        failed.iter(),
+        format,
+        |(url, error)| format!("{} {}\n", url.as_str(), error),
+        |(url, error)| FailedEntry {
+            url: url.as_str(),
+            error,
+        },
+    );
+    write_export(file_name, "failed urls", content);
+}
+
+fn export_disallowed(file_name: &str, disallowed: &[(Url, String)], format: Format) {
+    let content = format_records(
+        disallowed.iter(),
+        format,
+        |(url, rule)| format!("{} Disallow: {}\n", url.as_str(), rule),
+        |(url, rule)| DisallowedEntry {
+            url: url.as_str(),
+            rule,
+        },
+    );
+    write_export(file_name, "disallowed urls", content);
+}
+
+fn export_status(file_name: &str, statuses: &BTreeMap<Url, u16>, format: Format) {
+    let content = format_records(
+        statuses.iter(),
+        format,
+        |(url, status)| format!("{} {}\n", url.as_str(), status),
+        |(url, status)| StatusEntry {
+            url: url.as_str(),
+            status: *status,
+        },
+    );
+    write_export(file_name, "status codes", content);
+}
+
+/// Exports broken links. The json export groups them by the page they were found on
+fn export_broken(file_name: &str, broken: &BTreeMap<&str, Vec<BrokenLink>>, format: Format) {
+    let links = broken
+        .iter()
+        .flat_map(|(page, links)| links.iter().map(move |link| (*page, link)));
+    let content = match format {
+        Format::Json => serde_json::to_string_pretty(broken).map_err(|e| e.to_string()),
+        _ => format_records(
+            links,
+            format,
+            |(page, link)| {
+                let status = match (link.status, link.error) {
+                    (Some(status), _) => status.to_string(),
+                    (None, error) => error.unwrap_or("").to_string(),
+                };
+                format!("{} {} {}\n", page, link.url, status)
+            },
+            |(page, link)| BrokenEntry {
+                page,
+                url: link.url,
+                status: link.status,
+                error: link.error,
+            },
+        ),
+    };
+    write_export(file_name, "broken links", content);
+}
+
+/// Sends every record to all loggers that accept it
+struct TeeLogger(Vec<env_logger::Logger>);
+
+impl log::Log for TeeLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0.iter().any(|x| x.enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        for logger in &self.0 {
+            if logger.matches(record) {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for logger in &self.0 {
+            logger.flush();
+        }
+    }
+}
+
+/// Logs to stderr, and to `--log-file` if given
+fn init_logger(args: &Args) {
+    let builder = |default_filter: &str| {
+        let mut builder = env_logger::Builder::from_env(
+            env_logger::Env::default().default_filter_or(default_filter),
+        );
+        if args.log_format == LogFormat::Json {
+            builder.format(|buf, record| {
+                let entry = serde_json::json!({
+                    "timestamp": buf.timestamp().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", entry)
+            });
         }
+        builder
     };
 
-    if args.download {
-        match save_document(url, is_html, &response_bytes) {
-            Ok(_) => {}
+    // Keeps the progress bar from being drowned out by "Found url" messages
+    let mut loggers = vec![builder(match args.progress {
+        true => "warn",
+        false => "info",
+    })
+    .build()];
+    if let Some(log_file) = &args.log_file {
+        let file = match fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+        {
+            Ok(x) => x,
             Err(e) => {
-                warn!("Cannot save document: {}: {}", url, e);
-                return;
+                eprintln!("Cannot open log file: {}: {}", log_file.display(), e);
+                exit(1);
             }
-        }
+        };
+        loggers.push(
+            builder("info")
+                .target(env_logger::Target::Pipe(Box::new(file)))
+                .write_style(env_logger::WriteStyle::Never)
+                .build(),
+        );
     }
 
-    let mut found: Vec<Url> = vec![];
+    let max_level = loggers.iter().map(|x| x.filter()).max();
+    log::set_max_level(max_level.unwrap_or(log::LevelFilter::Off));
+    if let Err(e) = log::set_boxed_logger(Box::new(TeeLogger(loggers))) {
+        eprintln!("Cannot set logger: {}", e);
+    }
+}
 
-    if !is_html {
-        return;
+#[tokio::main]
+async fn main() {
+    let matches = parse_matches(std::env::args_os().collect()).unwrap_or_else(|e| e.exit());
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(politeness) = args.politeness {
+        politeness.apply(&mut args, &matches);
     }
-    let response_text = String::from_utf8_lossy(&response_bytes);
+    init_logger(&args);
+    trace!("{:?}", args);
 
-    found.extend(match get_urls_from_document(&response_text) {
-        Ok(x) => x
+    for (files, patterns) in [
+        (&args.include_from, &mut args.include_regex),
+        (&args.exclude_from, &mut args.exclude_regex),
+    ] {
+        for file in files {
+            match load_patterns(file) {
+                Ok(x) => patterns.extend(x),
+                Err(e) => {
+                    error!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+    }
+
+    trace!("Parsing url...");
+    let mut lines = vec![];
+    match args.url.as_deref() {
+        Some("-") => lines.extend(io::stdin().lines().map_while(Result::ok)),
+        Some(x) => lines.push(x.to_string()),
+        None => {}
+    }
+    if let Some(seeds_file) = &args.seeds_file {
+        match fs::read_to_string(seeds_file) {
+            Ok(x) => lines.extend(x.lines().map(str::to_string)),
+            Err(e) => {
+                error!("Cannot read file: {}: {}", seeds_file.display(), e);
+                exit(1);
+            }
+        }
+    }
+    let mut seeds = lines
+        .iter()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| {
+            Url::parse(x).unwrap_or_else(|_| {
+                error!("Cannot parse url: {}", x);
+                exit(1);
+            })
+        })
+        .collect::<Vec<_>>();
+    if seeds.is_empty() {
+        error!("No url to start from");
+        exit(1);
+    }
+    let seed = seeds.remove(0);
+
+    let crawler = Crawler::new(seed)
+        .seeds(seeds)
+        .download(args.download)
+        .output_dir(&args.output_dir)
+        .overwrite(args.overwrite)
+        .save_headers(args.save_headers)
+        .mirror(args.mirror)
+        .if_modified(args.if_modified)
+        .skip_downloaded(args.skip_downloaded)
+        .download_types(args.download_types.clone())
+        .max_file_size(args.max_file_size)
+        .max_bandwidth(args.max_bandwidth)
+        .head_first(args.head_first)
+        .crawl_external(args.crawl_external)
+        .include_subdomains(args.include_subdomains)
+        .internal_domains(args.internal_domains.clone())
+        .allow_domains(args.allow_domains.clone())
+        .deny_domains(args.deny_domains.clone())
+        .max_url_length(args.max_url_length as usize)
+        .max_path_segments(args.max_path_segments)
+        .exclude(args.exclude.clone())
+        .exclude_regex(args.exclude_regex.clone())
+        .include_regex(args.include_regex.clone())
+        .allow_ext(args.allow_ext.clone())
+        .deny_ext(args.deny_ext.clone())
+        .timeout(args.timeout)
+        .delay_jitter(args.delay_jitter)
+        .ignore_robots(args.ignore_robots)
+        .max_depth(args.max_depth)
+        .max_pages(args.max_pages)
+        .per_host_max_pages(args.per_host_max_pages)
+        .max_hosts(args.max_hosts)
+        .max_time(args.max_time)
+        .user_agent(&args.user_agent)
+        .respect_nofollow(args.respect_nofollow)
+        .same_path(args.same_path)
+        .request_timeout(args.request_timeout)
+        .retries(args.retries)
+        .retry_backoff(args.retry_backoff)
+        .check_links(args.check_links)
+        .concurrency(args.concurrency)
+        .per_host_concurrency(args.per_host_concurrency)
+        .deterministic(args.deterministic)
+        .strategy(args.strategy)
+        .use_sitemap(args.use_sitemap)
+        .dry_run(args.dry_run)
+        .proxy(args.proxy.clone())
+        .insecure(args.insecure)
+        .client_cert(args.client_cert.clone())
+        .client_key(args.client_key.clone())
+        .local_address(if args.ipv4_only {
+            Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED))
+        } else if args.ipv6_only {
+            Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED))
+        } else {
+            None
+        })
+        .resolve(args.resolve.clone())
+        .headers(args.headers.iter().cloned().collect())
+        .send_referer(args.send_referer)
+        .cross_origin_referer(args.cross_origin_referer)
+        .cookies(args.cookies.clone())
+        .cookie_file(args.cookie_file.clone())
+        .auth(args.auth.clone())
+        .max_redirects(args.max_redirects)
+        .no_redirects(args.no_redirects)
+        .dedupe_canonical(args.dedupe_canonical)
+        .sort_query(args.sort_query)
+        .keep_query(args.keep_query)
+        .dedupe_content(args.dedupe_content)
+        .state_file(args.state_file.clone())
+        .http_cache(args.http_cache.clone())
+        .events(args.events.clone())
+        .live_exports(match args.export_live {
+            true => [
+                (&args.export, Scope::All),
+                (&args.export_internal, Scope::Internal),
+                (&args.export_external, Scope::External),
+            ]
             .into_iter()
-            .map(|relative_url| url.join(&relative_url).unwrap()),
-        Err(e) => {
-            warn!("Cannot get urls from document: {}: {}", url, e);
-            return;
+            .filter_map(|(file_name, scope)| file_name.as_ref().map(|x| (PathBuf::from(x), scope)))
+            .collect(),
+            false => vec![],
+        })
+        .scan_js(args.scan_js)
+        .html_only(args.html_only)
+        .first_party_assets(args.first_party_assets)
+        .respect_robots_meta(args.respect_robots_meta)
+        .exclude_noindex(args.exclude_noindex)
+        .build()
+        .unwrap_or_else(|e| {
+            error!("{}", e);
+            exit(1);
+        });
+
+    // The first Ctrl-C stops the crawl and keeps what was found so far, the second one exits
+    let stop = crawler.stop_flag();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Stopping crawl. Press Ctrl-C again to exit immediately");
+            stop.store(true, Ordering::Relaxed);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            exit(130);
         }
     });
 
-    // Recurse
-    thread::scope(|s| {
-        let mut urls_locked = urls.lock().unwrap();
-
-        for mut i in found {
-            i = Url::parse(i.as_ref().split('?').next().unwrap_or(i.as_ref())).unwrap(); // Unreachable .unwrap()
-            i = Url::parse(i.as_ref().split('#').next().unwrap_or(i.as_ref())).unwrap(); // Unreachable .unwrap()
-
-            if !urls_locked.iter().any(|x| x.as_str() == i.as_str())
-                && !args.exclude.iter().any(|j| i.path().starts_with(j))
-            {
-                info!("Found url: {}", i);
-                urls_locked.push(i.clone());
-                if url.domain() == i.domain() || args.crawl_external {
-                    trace!("Url is internal. Crawling: {}", i.to_string());
-                    {
-                        let urls = urls.clone();
-                        let latest_request = latest_request.clone();
-
-                        s.spawn(move || {
-                            crawl(&i, urls, args, latest_request);
-                        });
-                    }
+    let status_server = match args.status_port {
+        Some(port) => match TcpListener::bind((args.status_host, port)).await {
+            Ok(listener) => {
+                info!(
+                    "Serving status at http://{}/status",
+                    SocketAddr::from((args.status_host, port))
+                );
+                Some(tokio::spawn(serve_status(
+                    listener,
+                    crawler.progress(),
+                    time::Instant::now(),
+                )))
+            }
+            Err(e) => {
+                error!("Cannot listen on port {}: {}", port, e);
+                exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let progress_bar = args.progress.then(|| {
+        let bar = ProgressBar::new_spinner();
+        let progress = crawler.progress();
+        let task = tokio::spawn({
+            let bar = bar.clone();
+            async move {
+                let start = time::Instant::now();
+                loop {
+                    let fetched = progress.fetched.load(Ordering::Relaxed);
+                    bar.set_message(format!(
+                        "{} fetched, {} found, {} queued, {:.1} requests/s",
+                        fetched,
+                        progress.found.load(Ordering::Relaxed),
+                        progress.queued.load(Ordering::Relaxed),
+                        fetched as f64 / start.elapsed().as_secs_f64().max(0.001)
+                    ));
+                    bar.tick();
+                    tokio::time::sleep(time::Duration::from_millis(200)).await;
                 }
             }
-        }
+        });
+        (bar, task)
     });
-}
 
-fn main() {
-    env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
+    let result = crawler.run().await;
+    if let Some(task) = status_server {
+        task.abort();
+    }
+    if let Some((bar, task)) = progress_bar {
+        task.abort();
+        bar.finish_and_clear();
+    }
+    if crawler.stop_flag().load(Ordering::Relaxed) {
+        info!("Crawl was stopped early. Results are incomplete");
+    }
+    let found_urls = &result.urls;
 
-    debug!("Parsing arguments...");
-    let args = Args::parse();
-    trace!("{:?}", args);
+    if let Some(max_pages) = args.max_pages {
+        info!("Crawled {} of max {} pages", found_urls.len(), max_pages);
+    }
 
-    let found_urls: Arc<Mutex<Vec<Url>>> = Arc::new(Mutex::new(vec![]));
-    trace!("Parsing url...");
-    let document = Url::parse(&args.url).unwrap_or_else(|_| {
-        error!("Cannot parse url: {}", args.url);
-        exit(1);
-    });
+    let internal_urls = &result.internal;
+    let external_urls = &result.external;
 
-    debug!("Crawling...");
-    crawl(
-        &document,
-        found_urls.clone(),
-        &args,
-        Arc::new(Mutex::new(time::Instant::now())),
-    );
+    if !args.quiet {
+        println!("{}", "Internal urls:".to_string().bright_green());
+        for url in internal_urls {
+            println!("{}", url.as_str());
+        }
 
-    let mut found_urls = found_urls.lock().unwrap();
-    found_urls.sort();
+        println!("{}", "External urls:".to_string().red());
+        for url in external_urls {
+            println!("{}", url.as_str());
+        }
+    }
 
-    let mut internal_urls = Vec::new();
-    let mut external_urls = Vec::new();
+    // Printed even with --quiet, as it is the point of a dry run
+    if args.dry_run {
+        println!("{}", "Links on the seed page:".to_string().bright_blue());
+        for (url, reason) in &result.decisions {
+            match reason {
+                None => println!("  {} {}", "crawl".green(), url),
+                Some(reason) => println!("  {} {} ({})", "skip ".red(), url, reason),
+            }
+        }
+    }
 
-    for url in found_urls.iter() {
-        if url.domain() == document.domain() {
-            internal_urls.push(url);
-        } else {
-            external_urls.push(url);
+    /// Groups internal pages by a metadata field, keeping the values that more than one page has
+    fn same_metadata<'a>(
+        result: &'a CrawlResult,
+        field: impl Fn(&'a Metadata) -> Option<&'a str>,
+    ) -> BTreeMap<&'a str, Vec<&'a Url>> {
+        let mut groups: BTreeMap<&str, Vec<&Url>> = BTreeMap::new();
+        for url in &result.internal {
+            if let Some(value) = result.metadata.get(url).and_then(&field) {
+                groups.entry(value).or_default().push(url);
+            }
         }
+        groups.retain(|_, urls| urls.len() > 1);
+        groups
     }
 
-    println!("{}", "Internal urls:".to_string().bright_green());
-    for url in &internal_urls {
-        println!("{}", url.as_str());
+    if args.report_duplicates {
+        let reports = [
+            (
+                "Duplicate titles:",
+                same_metadata(&result, |x| x.title.as_deref()),
+            ),
+            (
+                "Duplicate descriptions:",
+                same_metadata(&result, |x| x.description.as_deref()),
+            ),
+        ];
+        for (heading, groups) in reports {
+            println!("{}", heading.to_string().yellow());
+            for (value, urls) in groups {
+                println!("{:?}", value);
+                for url in urls {
+                    println!("  {}", url);
+                }
+            }
+        }
     }
 
-    println!("{}", "External urls:".to_string().red());
-    for url in &external_urls {
-        println!("{}", url.as_str());
+    /// Pages with more than `threshold` distinct links and how many, most links first
+    fn link_counts(edges: &[(Url, Url)], threshold: usize) -> Vec<(&Url, usize)> {
+        let mut links: BTreeMap<&Url, BTreeSet<&Url>> = BTreeMap::new();
+        for (from, to) in edges {
+            links.entry(from).or_default().insert(to);
+        }
+        let mut counts: Vec<(&Url, usize)> = links
+            .into_iter()
+            .map(|(page, links)| (page, links.len()))
+            .filter(|(_, count)| *count > threshold)
+            .collect();
+        counts.sort_by_key(|&(_, count)| Reverse(count));
+        counts
     }
 
-    fn export<T: Borrow<Url>>(file_name: &str, found_urls: &[T]) {
-        let mut file = match fs::File::create(file_name) {
-            Ok(x) => x,
-            Err(e) => {
-                error!("Cannot create file: {}: {}", file_name, e);
-                return;
-            }
-        };
+    if let Some(threshold) = args.report_link_count {
+        println!(
+            "{}",
+            format!("Pages with more than {} links:", threshold).yellow()
+        );
+        for (page, count) in link_counts(&result.edges, threshold) {
+            println!("{:>6} {}", count, page);
+        }
+    }
 
-        for url in found_urls.iter() {
-            match file.write_all(format!("{}\n", url.borrow().as_str()).as_bytes()) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Cannot write to file: {}: {}", file_name, e);
-                    return;
+    let edges = &result.edges;
+    let failed = &result.failed;
+    let statuses = &result.statuses;
+
+    if args.check_links {
+        let broken = broken_links(edges, statuses, failed, &result.redirects);
+
+        if !args.quiet {
+            println!("{}", "Broken links:".to_string().yellow());
+            for (page, links) in &broken {
+                println!("{}", page);
+                for link in links {
+                    match (link.status, link.error) {
+                        (Some(status), _) => println!("  {} {}", status, link.url),
+                        (None, Some(error)) => println!("  {} ({})", link.url, error),
+                        (None, None) => println!("  {}", link.url),
+                    }
                 }
             }
         }
 
-        info!("Exported to file: {}", file_name);
+        if let Some(file_name) = &args.export_broken {
+            export_broken(file_name, &broken, args.format);
+        }
+    }
+
+    if let Some(file_name) = &args.export {
+        export_urls(file_name, found_urls, args.format, &result);
+    }
+    if let Some(file_name) = &args.export_internal {
+        export_urls(file_name, internal_urls, args.format, &result);
+    }
+    if let Some(file_name) = &args.export_external {
+        export_urls(file_name, external_urls, args.format, &result);
+    }
+
+    if let Some(file_name) = &args.export_graph {
+        export_graph(file_name, edges, args.format);
     }
 
-    if let Some(file_name) = args.export {
-        export(&file_name, &found_urls);
+    if let Some(file_name) = &args.export_failed {
+        export_failed(file_name, failed, args.format);
     }
-    if let Some(file_name) = args.export_internal {
-        export(&file_name, &internal_urls);
+
+    if let Some(file_name) = &args.export_disallowed {
+        export_disallowed(file_name, &result.disallowed, args.format);
     }
-    if let Some(file_name) = args.export_external {
-        export(&file_name, &external_urls);
+
+    if let Some(file_name) = &args.export_status {
+        export_status(file_name, statuses, args.format);
+    }
+
+    if let Some(file_name) = &args.export_sitemap {
+        // Only pages that were actually reachable. 304 means an unchanged download was reused
+        let reachable = internal_urls
+            .iter()
+            .filter(|url| {
+                statuses
+                    .get(*url)
+                    .is_some_and(|x| (200..300).contains(x) || *x == 304)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        let content = sitemap::to_xml(&reachable, &result.last_modified);
+        write_export(file_name, "sitemap", Ok(content));
+    }
+
+    let stats = &result.stats;
+    println!("{}", "Summary:".to_string().bright_blue());
+    println!("  Pages fetched: {}", stats.fetched);
+    println!("  Internal urls: {}", internal_urls.len());
+    println!("  External urls: {}", external_urls.len());
+    if args.download {
+        println!("  Files downloaded: {}", stats.downloads);
+    }
+    if args.dedupe_content {
+        println!("  Duplicate files: {}", result.duplicates.len());
+    }
+    println!("  Data received: {}", format_bytes(stats.bytes));
+    println!("  Errors: {}", stats.errors);
+    println!("  Elapsed: {:.1}s", stats.elapsed.as_secs_f64());
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64;
+    let mut unit = "B";
+    for x in UNITS {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = x;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(x: &str) -> Url {
+        Url::parse(x).unwrap()
+    }
+
+    #[test]
+    fn config_keys_are_flag_names() {
+        let path =
+            std::env::temp_dir().join(format!("web-crawler-config-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{
+                "url": "https://example.com",
+                "header": ["X-Test: 1"],
+                "internal-domain": ["example.com"],
+                "cookie": ["a=b"],
+                "allow-domain": ["example.org"],
+                "deny-domain": ["example.net"],
+                "max-depth": 3
+            }"#,
+        )
+        .unwrap();
+        let matches = Args::command()
+            .try_get_matches_from(["web-crawler", "--config", path.to_str().unwrap()])
+            .unwrap();
+        let args = config_args(&path, &matches);
+        fs::remove_file(&path).unwrap();
+
+        let mut args = args.unwrap();
+        args.sort();
+        assert_eq!(
+            args,
+            [
+                "--allow-domain=example.org",
+                "--cookie=a=b",
+                "--deny-domain=example.net",
+                "--header=X-Test: 1",
+                "--internal-domain=example.com",
+                "--max-depth=3",
+                "https://example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn config_satisfies_requirements_of_flags() {
+        let path =
+            std::env::temp_dir().join(format!("web-crawler-requires-{}.json", std::process::id()));
+        fs::write(&path, r#"{"download": true}"#).unwrap();
+        let matches = parse_matches(
+            [
+                "web-crawler",
+                "--config",
+                path.to_str().unwrap(),
+                "--save-headers",
+                "https://example.com",
+            ]
+            .map(OsString::from)
+            .to_vec(),
+        );
+        fs::remove_file(&path).unwrap();
+
+        let matches = matches.unwrap();
+        assert!(matches.get_flag("download"));
+        assert!(matches.get_flag("save_headers"));
+    }
+
+    #[test]
+    fn broken_links_follow_redirects() {
+        let edges = vec![(url("http://x/"), url("http://x/redir"))];
+        let statuses = BTreeMap::from([(url("http://x/"), 200), (url("http://x/gone"), 404)]);
+        let redirects = BTreeMap::from([(url("http://x/redir"), url("http://x/gone"))]);
+
+        let broken = broken_links(&edges, &statuses, &[], &redirects);
+        let links = &broken["http://x/"];
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].url, "http://x/redir");
+        assert_eq!(links[0].status, Some(404));
     }
 }
@@ -0,0 +1,420 @@
+use log::{debug, trace, warn};
+use reqwest::{header::HeaderMap, Client};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time,
+};
+use url::Url;
+
+#[derive(Debug, Clone)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Group {
+    user_agents: Vec<String>,
+    rules: Vec<Rule>,
+    crawl_delay: Option<time::Duration>,
+}
+
+/// Parsed contents of a single robots.txt file
+#[derive(Debug, Clone, Default)]
+pub struct Robots {
+    groups: Vec<Group>,
+}
+
+impl Robots {
+    pub fn parse(content: &str) -> Robots {
+        let mut groups: Vec<Group> = Vec::new();
+        let mut last_was_user_agent = false;
+
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let (key, value) = match line.split_once(':') {
+                Some((key, value)) => (key.trim().to_lowercase(), value.trim()),
+                None => continue,
+            };
+
+            if key == "user-agent" {
+                if !last_was_user_agent {
+                    groups.push(Group::default());
+                }
+                if let Some(group) = groups.last_mut() {
+                    group.user_agents.push(value.to_lowercase());
+                }
+                last_was_user_agent = true;
+                continue;
+            }
+            last_was_user_agent = false;
+
+            let group = match groups.last_mut() {
+                Some(x) => x,
+                None => continue,
+            };
+            match key.as_str() {
+                // An empty Disallow means everything is allowed
+                "allow" | "disallow" if !value.is_empty() => {
+                    group.rules.push(Rule {
+                        allow: key == "allow",
+                        pattern: value.to_string(),
+                    });
+                }
+                "crawl-delay" => match value.parse::<f64>() {
+                    Ok(x) if x >= 0.0 => {
+                        group.crawl_delay = Some(time::Duration::from_secs_f64(x));
+                    }
+                    _ => debug!("Invalid Crawl-delay: {}", value),
+                },
+                _ => {}
+            }
+        }
+
+        Robots { groups }
+    }
+
+    /// Groups that apply to the user agent. Groups naming its product token, e.g. `web-crawler`
+    /// for `web-crawler/1.0`, are used over `*`. All groups for the same agent are combined
+    fn groups(&self, user_agent: &str) -> Vec<&Group> {
+        let token = user_agent
+            .split(|x: char| x == '/' || x.is_whitespace())
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        let named = |agent: &str| -> Vec<&Group> {
+            self.groups
+                .iter()
+                .filter(|group| group.user_agents.iter().any(|x| x == agent))
+                .collect()
+        };
+
+        match named(&token) {
+            x if x.is_empty() || token.is_empty() => named("*"),
+            x => x,
+        }
+    }
+
+    /// The rule that decides whether `url` may be crawled, if any matches
+    fn matching_rule(&self, url: &Url, user_agent: &str) -> Option<&Rule> {
+        let groups = self.groups(user_agent);
+        let path = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+
+        // The longest matching rule wins, Allow wins ties
+        let mut best: Option<&Rule> = None;
+        for rule in groups.iter().flat_map(|group| &group.rules) {
+            if !pattern_matches(&rule.pattern, &path) {
+                continue;
+            }
+            best = match best {
+                Some(x)
+                    if x.pattern.len() > rule.pattern.len()
+                        || (x.pattern.len() == rule.pattern.len() && x.allow) =>
+                {
+                    Some(x)
+                }
+                _ => Some(rule),
+            };
+        }
+
+        best
+    }
+
+    pub fn is_allowed(&self, url: &Url, user_agent: &str) -> bool {
+        self.matching_rule(url, user_agent)
+            .is_none_or(|rule| rule.allow)
+    }
+
+    /// Pattern of the `Disallow` rule that forbids crawling `url`, or `None` if it is allowed
+    pub fn disallowed_by(&self, url: &Url, user_agent: &str) -> Option<String> {
+        self.matching_rule(url, user_agent)
+            .filter(|rule| !rule.allow)
+            .map(|rule| rule.pattern.clone())
+    }
+
+    pub fn crawl_delay(&self, user_agent: &str) -> Option<time::Duration> {
+        self.groups(user_agent)
+            .iter()
+            .find_map(|group| group.crawl_delay)
+    }
+}
+
+/// Indexing directives a page declares about itself in `X-Robots-Tag` headers or robots meta tags
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Directives {
+    /// The page shouldn't be listed
+    pub noindex: bool,
+    /// The links of the page shouldn't be followed
+    pub nofollow: bool,
+}
+
+impl Directives {
+    /// Parses a comma-separated list like `noindex, nofollow`. `none` means both
+    pub fn parse(content: &str) -> Directives {
+        let mut directives = Directives::default();
+        for directive in content.split(',') {
+            match directive.trim().to_lowercase().as_str() {
+                "noindex" => directives.noindex = true,
+                "nofollow" => directives.nofollow = true,
+                "none" => {
+                    directives.noindex = true;
+                    directives.nofollow = true;
+                }
+                _ => {}
+            }
+        }
+        directives
+    }
+
+    /// Directives that are set in either
+    pub fn union(self, other: Directives) -> Directives {
+        Directives {
+            noindex: self.noindex || other.noindex,
+            nofollow: self.nofollow || other.nofollow,
+        }
+    }
+
+    /// Directives of all `X-Robots-Tag` headers that apply to `user_agent`. A header can be
+    /// limited to one crawler with a prefix, e.g. `googlebot: nofollow`
+    pub fn from_headers(headers: &HeaderMap, user_agent: &str) -> Directives {
+        let user_agent = user_agent.to_lowercase();
+        let mut directives = Directives::default();
+
+        for value in headers.get_all("x-robots-tag") {
+            let value = match value.to_str() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            // Directives like `unavailable_after: <date>` and `max-snippet:20` contain a colon too
+            let is_agent =
+                |x: &str| !x.is_empty() && !x.contains([' ', ',', '_']) && !x.starts_with("max-");
+            let content = match value.split_once(':') {
+                Some((agent, content)) if is_agent(agent.trim()) => {
+                    if !user_agent.contains(&agent.trim().to_lowercase()) {
+                        continue;
+                    }
+                    content
+                }
+                _ => value,
+            };
+
+            directives = directives.union(Directives::parse(content));
+        }
+
+        directives
+    }
+}
+
+/// Matches a robots.txt path pattern, supporting `*` wildcards and a `$` end anchor
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(x) => (x, true),
+        None => (pattern, false),
+    };
+
+    let mut parts = pattern.split('*');
+    let mut rest = match path.strip_prefix(parts.next().unwrap_or("")) {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    for (i, part) in parts.iter().enumerate() {
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    !anchored || rest.is_empty()
+}
+
+async fn fetch(client: &Client, url: &Url) -> Robots {
+    let mut robots_url = url.clone();
+    robots_url.set_path("/robots.txt");
+    robots_url.set_query(None);
+    robots_url.set_fragment(None);
+
+    trace!("Fetching robots.txt: {}", robots_url);
+    let response = match client.get(robots_url.as_str()).send().await {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("Cannot request robots.txt: {}: {}", robots_url, e);
+            return Robots::default();
+        }
+    };
+    if !response.status().is_success() {
+        debug!(
+            "No robots.txt ({}): {}",
+            response.status().as_u16(),
+            robots_url
+        );
+        return Robots::default();
+    }
+
+    match response.text().await {
+        Ok(x) => Robots::parse(&x),
+        Err(e) => {
+            warn!("Cannot read robots.txt: {}: {}", robots_url, e);
+            Robots::default()
+        }
+    }
+}
+
+/// robots.txt files fetched so far, keyed by host
+#[derive(Debug, Default)]
+pub struct RobotsCache {
+    hosts: Mutex<HashMap<String, Arc<Robots>>>,
+}
+
+impl RobotsCache {
+    pub async fn get(&self, client: &Client, url: &Url) -> Arc<Robots> {
+        let host = url.host_str().unwrap_or("").to_string();
+
+        if let Some(robots) = self.hosts.lock().unwrap().get(&host) {
+            return robots.clone();
+        }
+
+        let robots = Arc::new(fetch(client, url).await);
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert(robots)
+            .clone()
+    }
+
+    pub async fn is_allowed(&self, client: &Client, url: &Url, user_agent: &str) -> bool {
+        self.get(client, url).await.is_allowed(url, user_agent)
+    }
+
+    pub async fn disallowed_by(
+        &self,
+        client: &Client,
+        url: &Url,
+        user_agent: &str,
+    ) -> Option<String> {
+        self.get(client, url).await.disallowed_by(url, user_agent)
+    }
+
+    pub async fn crawl_delay(
+        &self,
+        client: &Client,
+        url: &Url,
+        user_agent: &str,
+    ) -> Option<time::Duration> {
+        self.get(client, url).await.crawl_delay(user_agent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(robots: &str, path: &str, user_agent: &str) -> bool {
+        let url = Url::parse("http://x").unwrap().join(path).unwrap();
+        Robots::parse(robots).is_allowed(&url, user_agent)
+    }
+
+    #[test]
+    fn parse_reads_groups() {
+        let robots = Robots::parse(
+            "# Comment\nUser-agent: A\nUser-Agent: b # trailing\nDisallow: /x\nAllow:\nDisallow:\n\
+             Crawl-delay: 1.5\n\nuser-agent: *\ndisallow: /y\ncrawl-delay: nope\nNot a directive\n",
+        );
+        assert_eq!(robots.groups.len(), 2);
+        assert_eq!(robots.groups[0].user_agents, ["a", "b"]);
+        assert_eq!(robots.groups[0].rules.len(), 1);
+        assert_eq!(
+            robots.groups[0].crawl_delay,
+            Some(time::Duration::from_millis(1500))
+        );
+        assert_eq!(robots.groups[1].user_agents, ["*"]);
+        assert_eq!(robots.groups[1].rules[0].pattern, "/y");
+        assert_eq!(robots.groups[1].crawl_delay, None);
+    }
+
+    #[test]
+    fn rules_before_user_agent_are_ignored() {
+        assert!(allowed(
+            "Disallow: /\nUser-agent: *\nDisallow: /x",
+            "/",
+            "a"
+        ));
+    }
+
+    #[test]
+    fn pattern_matches_prefixes() {
+        assert!(pattern_matches("/a", "/a"));
+        assert!(pattern_matches("/a", "/ab/c"));
+        assert!(!pattern_matches("/a", "/b/a"));
+    }
+
+    #[test]
+    fn pattern_matches_wildcards() {
+        assert!(pattern_matches("/*.php", "/a/b.php?x=1"));
+        assert!(pattern_matches("/a*b*c", "/a-b-c"));
+        assert!(!pattern_matches("/a*b*c", "/a-c-b"));
+        assert!(pattern_matches("*", "/anything"));
+    }
+
+    #[test]
+    fn pattern_matches_end_anchor() {
+        assert!(pattern_matches("/*.php$", "/a.php"));
+        assert!(!pattern_matches("/*.php$", "/a.php?x=1"));
+        assert!(pattern_matches("/a$", "/a"));
+        assert!(!pattern_matches("/a$", "/ab"));
+    }
+
+    #[test]
+    fn longest_rule_wins() {
+        let robots = "User-agent: *\nDisallow: /a\nAllow: /a/b\nDisallow: /a/b/c";
+        assert!(!allowed(robots, "/a/x", "a"));
+        assert!(allowed(robots, "/a/b/x", "a"));
+        assert!(!allowed(robots, "/a/b/c", "a"));
+        assert!(allowed(robots, "/b", "a"));
+    }
+
+    #[test]
+    fn allow_wins_ties() {
+        assert!(allowed("User-agent: *\nDisallow: /a\nAllow: /a", "/a", "a"));
+        assert!(allowed("User-agent: *\nAllow: /a\nDisallow: /a", "/a", "a"));
+    }
+
+    #[test]
+    fn named_group_is_used_over_wildcard() {
+        let robots = "User-agent: *\nDisallow: /\n\nUser-agent: Web-Crawler\nDisallow: /x";
+        assert!(allowed(robots, "/a", "web-crawler/1.0"));
+        assert!(!allowed(robots, "/x", "web-crawler/1.0"));
+        assert!(!allowed(robots, "/a", "other/1.0"));
+    }
+
+    #[test]
+    fn group_matches_product_token_only() {
+        let robots = "User-agent: crawler\nDisallow: /";
+        assert!(allowed(robots, "/", "web-crawler/1.0"));
+        assert!(allowed(robots, "/", "Mozilla/5.0 (compatible; crawler)"));
+        assert!(!allowed(robots, "/", "Crawler/2.0"));
+    }
+
+    #[test]
+    fn groups_of_same_agent_are_merged() {
+        let robots = Robots::parse(
+            "User-agent: a\nDisallow: /x\n\nUser-agent: *\nDisallow: /y\n\n\
+             User-agent: a\nDisallow: /z\nCrawl-delay: 2",
+        );
+        let url = |x| Url::parse("http://x").unwrap().join(x).unwrap();
+        assert!(!robots.is_allowed(&url("/x"), "a"));
+        assert!(!robots.is_allowed(&url("/z"), "a"));
+        assert!(robots.is_allowed(&url("/y"), "a"));
+        assert_eq!(robots.crawl_delay("a"), Some(time::Duration::from_secs(2)));
+        assert_eq!(robots.crawl_delay("b"), None);
+    }
+}
@@ -0,0 +1,174 @@
+use log::{debug, trace, warn};
+use quick_xml::{escape::escape, events::Event, Reader};
+use reqwest::Client;
+use std::collections::BTreeMap;
+use url::Url;
+
+/// Maximum number of sitemap files fetched, so sitemap indexes can't loop forever
+const MAX_SITEMAPS: usize = 50;
+
+/// Parsed contents of a single sitemap.xml file
+#[derive(Debug, Clone, Default)]
+pub struct Sitemap {
+    /// Page urls from `<url><loc>` entries
+    pub urls: Vec<Url>,
+    /// Nested sitemaps from `<sitemapindex>` entries
+    pub sitemaps: Vec<Url>,
+}
+
+impl Sitemap {
+    pub fn parse(xml: &str) -> Result<Sitemap, String> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        let mut sitemap = Sitemap::default();
+        let mut in_sitemap = false;
+        let mut in_loc = false;
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(e)) => match e.local_name().as_ref() {
+                    b"sitemap" => in_sitemap = true,
+                    b"loc" => in_loc = true,
+                    _ => {}
+                },
+                Ok(Event::End(e)) => match e.local_name().as_ref() {
+                    b"sitemap" => in_sitemap = false,
+                    b"loc" => in_loc = false,
+                    _ => {}
+                },
+                Ok(Event::Text(e)) if in_loc => {
+                    let text = match e.unescape() {
+                        Ok(x) => x,
+                        Err(e) => {
+                            debug!("Cannot unescape sitemap entry: {}", e);
+                            continue;
+                        }
+                    };
+                    match Url::parse(text.trim()) {
+                        Ok(url) if in_sitemap => sitemap.sitemaps.push(url),
+                        Ok(url) => sitemap.urls.push(url),
+                        Err(e) => debug!("Invalid url in sitemap: {}: {}", text, e),
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => {
+                    return Err(format!(
+                        "Cannot parse sitemap at position {}: {}",
+                        reader.buffer_position(),
+                        e
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(sitemap)
+    }
+}
+
+/// Fetches `/sitemap.xml` of the seed's host and every sitemap it references. Returns all page
+/// urls found, or none if there is no usable sitemap
+pub async fn fetch(client: &Client, seed: &Url) -> Vec<Url> {
+    let mut sitemap_url = seed.clone();
+    sitemap_url.set_path("/sitemap.xml");
+    sitemap_url.set_query(None);
+    sitemap_url.set_fragment(None);
+
+    let mut urls = Vec::new();
+    let mut pending = vec![sitemap_url];
+    let mut fetched = 0;
+    while let Some(sitemap_url) = pending.pop() {
+        if fetched >= MAX_SITEMAPS {
+            warn!("Not fetching more than {} sitemaps", MAX_SITEMAPS);
+            break;
+        }
+        fetched += 1;
+
+        trace!("Fetching sitemap: {}", sitemap_url);
+        let response = match client.get(sitemap_url.as_str()).send().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Cannot request sitemap: {}: {}", sitemap_url, e);
+                continue;
+            }
+        };
+        if !response.status().is_success() {
+            debug!(
+                "No sitemap ({}): {}",
+                response.status().as_u16(),
+                sitemap_url
+            );
+            continue;
+        }
+        let text = match response.text().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Cannot read sitemap: {}: {}", sitemap_url, e);
+                continue;
+            }
+        };
+
+        match Sitemap::parse(&text) {
+            Ok(sitemap) => {
+                urls.extend(sitemap.urls);
+                pending.extend(sitemap.sitemaps);
+            }
+            Err(e) => warn!("{}: {}", e, sitemap_url),
+        }
+    }
+
+    urls
+}
+
+/// Converts an HTTP date like `Wed, 21 Oct 2015 07:28:00 GMT` to the W3C format used by sitemaps
+fn http_date_to_w3c(date: &str) -> Option<String> {
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+    let month = match month.to_lowercase().as_str() {
+        "jan" => 1,
+        "feb" => 2,
+        "mar" => 3,
+        "apr" => 4,
+        "may" => 5,
+        "jun" => 6,
+        "jul" => 7,
+        "aug" => 8,
+        "sep" => 9,
+        "oct" => 10,
+        "nov" => 11,
+        "dec" => 12,
+        _ => return None,
+    };
+    let day: u32 = day.parse().ok()?;
+    let year: u32 = year.parse().ok()?;
+    if time.len() != 8 || !time.chars().all(|c| c.is_ascii_digit() || c == ':') {
+        return None;
+    }
+
+    Some(format!(
+        "{:04}-{:02}-{:02}T{}+00:00",
+        year, month, day, time
+    ))
+}
+
+/// Writes a sitemap.xml listing `urls`, with `<lastmod>` taken from their Last-Modified header
+/// when known
+pub fn to_xml(urls: &[Url], last_modified: &BTreeMap<Url, String>) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n",
+    );
+    for url in urls {
+        xml.push_str("  <url>\n");
+        xml.push_str(&format!("    <loc>{}</loc>\n", escape(url.as_str())));
+        if let Some(lastmod) = last_modified.get(url).and_then(|x| http_date_to_w3c(x)) {
+            xml.push_str(&format!("    <lastmod>{}</lastmod>\n", lastmod));
+        }
+        xml.push_str("  </url>\n");
+    }
+    xml.push_str("</urlset>\n");
+    xml
+}
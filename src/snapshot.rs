@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+use crate::{document::Metadata, download::write_atomically};
+
+/// Progress of a crawl as saved to a state file, so it can be resumed later
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub urls: Vec<Url>,
+    /// Normalized urls that were fetched
+    pub visited: HashSet<Url>,
+    /// Urls that still have to be crawled with their depth, including the ones that were being
+    /// crawled when the snapshot was taken
+    pub queue: Vec<(Url, u32)>,
+    pub edges: Vec<(Url, Url)>,
+    /// Missing in state files written by older versions
+    #[serde(default)]
+    pub depths: BTreeMap<Url, u32>,
+    #[serde(default)]
+    pub referrers: BTreeMap<Url, Url>,
+    pub statuses: BTreeMap<Url, u16>,
+    pub last_modified: BTreeMap<Url, String>,
+    pub failed: Vec<(Url, String)>,
+    pub redirects: BTreeMap<Url, Url>,
+    /// Pages marked noindex
+    #[serde(default)]
+    pub noindex: HashSet<Url>,
+    #[serde(default)]
+    pub metadata: BTreeMap<Url, Metadata>,
+    #[serde(default)]
+    pub disallowed: Vec<(Url, String)>,
+    /// Canonical url of every page that declared a different one
+    #[serde(default)]
+    pub canonicals: BTreeMap<Url, Url>,
+    /// First url and file saved with each sha256 hash. A list because json keys are strings
+    #[serde(default)]
+    pub content_hashes: Vec<([u8; 32], Url, PathBuf)>,
+    /// Downloads with the same content as an earlier one as (duplicate, original) pairs
+    #[serde(default)]
+    pub duplicates: Vec<(Url, Url)>,
+}
+
+impl Snapshot {
+    /// Loads a state file. Returns `None` if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Option<Snapshot>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = match fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Cannot read state file: {}: {}", path.display(), e));
+            }
+        };
+        match serde_json::from_str(&content) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) => Err(format!("Invalid state file: {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Writes the state file, keeping the earlier snapshot if the write is interrupted
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let content = match serde_json::to_string(self) {
+            Ok(x) => x,
+            Err(e) => {
+                return Err(format!("Cannot serialize state: {}", e));
+            }
+        };
+
+        write_atomically(path, content.as_bytes())
+    }
+}
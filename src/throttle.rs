@@ -0,0 +1,35 @@
+use std::{sync::Mutex, time};
+
+/// Limits how many bytes per second are read, shared by every worker. Works like a token bucket
+/// that holds at most one second worth of bytes
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_second: u64,
+    /// When the bytes read so far are paid off
+    paid_until: Mutex<time::Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: u64) -> Throttle {
+        Throttle {
+            bytes_per_second: bytes_per_second.max(1),
+            paid_until: Mutex::new(time::Instant::now()),
+        }
+    }
+
+    /// Counts `bytes` as read and waits until reading them doesn't go over the limit anymore
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut paid_until = self.paid_until.lock().unwrap();
+            let now = time::Instant::now();
+            // Unused time is only saved up for one second so idle periods don't allow huge bursts
+            let earliest = now.checked_sub(time::Duration::from_secs(1)).unwrap_or(now);
+            *paid_until = (*paid_until).max(earliest)
+                + time::Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+            paid_until.saturating_duration_since(now)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}